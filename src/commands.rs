@@ -1,76 +1,675 @@
+use std::sync::Arc;
 use anyhow::{Result, anyhow};
 use itertools::Itertools;
 use crate::telegram;
 use crate::utils;
 use telegram::IncomeMessage;
 use crate::ytdlp;
-use crate::user_state::{State, Mode, Quality, UserConfig, CutInterval};
+use crate::user_state::{State, Mode, Quality, UserConfig, Cut, CutInterval, CutMode, Oversize, Pending, AudioFormat};
 use crate::config::Config;
-use crate::format_chooser::{ChosenFormat, choose_format};
+use crate::format_chooser::{self, ChosenFormat, choose_format, find_format};
 use crate::ffmpeg;
+use crate::progress;
+use crate::settings_menu;
+use crate::format_picker;
+use crate::playlist_prompt;
+use crate::jobs::{Job, JobKind, JobQueue};
+use crate::rate_limit::{RateLimiter, Admission};
+use crate::file_cache::{MediaKey, RequestKey, CachedFile};
+use crate::mime;
+use telegram::{InputFile, SentFile, MediaMeta};
+
+
+/// Upload file reporting progress in status message, return stored file
+async fn upload(conf: &Config, job: &Job, mode: &Mode, as_file: bool, caption: String, file: InputFile, meta: &MediaMeta) -> Result<Option<SentFile>> {
+  let &Job {chat_id, message_id, reply_to, ..} = job;
+  let header = format!("Uploading {}...", caption);
+  telegram::edit_message_text(
+    &conf.bot_url(), chat_id, message_id, header.clone()).await?;
+  let (tx, rx) = progress::channel();
+  let send = async {
+    match (mode, as_file) {
+      (_, true) =>
+        telegram::send_document(&conf.bot_url(), chat_id, reply_to, caption.clone(), file, meta, tx).await,
+      (Mode::Video, false) =>
+        telegram::send_video(&conf.bot_url(), chat_id, reply_to, caption.clone(), file, meta, tx).await,
+      (Mode::Audio, false) =>
+        telegram::send_audio(&conf.bot_url(), chat_id, reply_to, caption.clone(), file, meta, tx).await,
+    }
+  };
+  let (res, ()) = tokio::join!(
+    send, progress::report(conf, chat_id, message_id, &header, rx));
+  res
+}
+
+/// Send file as media or document, falling back to document if telegram
+/// refuses media (unsupported container or codec)
+async fn send_file(conf: &Config, job: &Job, mode: &Mode, as_file: bool, caption: String, file: InputFile, meta: &MediaMeta) -> Result<Option<SentFile>> {
+  // containers telegram can not play (webm, mkv, flac) go as documents right away
+  let playable = match &file {
+    InputFile::Upload(path) | InputFile::Local(path) =>
+      mime::sniff_file(path).is_none_or(|x| x.playable()),
+    InputFile::FileId(_) => true,
+  };
+  if as_file || !playable {
+    return upload(conf, job, mode, true, caption, file, meta).await;
+  }
+  let res = upload(conf, job, mode, false, caption.clone(), file.clone(), meta).await;
+  match res {
+    // file_id is bound to the way file was stored, nothing to fall back to
+    Err(e) if !matches!(file, InputFile::FileId(_))
+      && telegram::telegram_error(&e).is_some_and(|x| x.is_media_rejected()) => {
+      log::warn!("Could not send {} as {:?}, sending as file: {}", file, mode, e);
+      upload(conf, job, mode, true, caption, file, meta).await
+    },
+    res => res,
+  }
+}
+
+/// Attributes and thumbnail of [file] to show in telegram, whatever could be found out.
+/// Audio thumbnail is made of [cover] if there is one.
+async fn media_meta(video: &ytdlp::Video, mode: &Mode, file: &String, cover: Option<&str>) -> MediaMeta {
+  let probe = ffmpeg::probe(file).await.unwrap_or_else(|e| {
+    log::warn!("Could not probe {}: {}", file, e);
+    ffmpeg::Probe::default()
+  });
+  let duration = probe.duration.or(video.duration);
+  let thumbnail = match (mode, cover.or(video.thumbnail.as_deref())) {
+    (Mode::Video, _) => ffmpeg::thumbnail(file, duration.map(|x| x / 10.0), file).await,
+    (Mode::Audio, Some(input)) => ffmpeg::thumbnail(input, None, file).await,
+    (Mode::Audio, None) => Err(anyhow!("no thumbnail URL")),
+  };
+  let thumbnail = thumbnail
+    .map_err(|e| log::warn!("Could not make thumbnail of {}: {}", file, e))
+    .ok();
+  MediaMeta {
+    duration: duration.map(|x| x.round() as i64),
+    width: probe.width.or(Some(video.width)),
+    height: probe.height.or(Some(video.height)),
+    performer: video.artist.clone().or(video.uploader.clone()),
+    title: Some(video.track.clone().unwrap_or(video.title.clone())),
+    thumbnail,
+    audio: *mode == Mode::Audio,
+  }
+}
+
+/// Resend media by file_id, forgetting it from cache if telegram refuses it.
+/// Return false if it could not be sent.
+async fn send_cached(conf: &Config, state: &State, job: &Job, key: &MediaKey, files: Vec<CachedFile>) -> bool {
+  for CachedFile {file_id, caption, document} in files {
+    let file = InputFile::FileId(file_id);
+    let meta = MediaMeta::default();
+    if let Err(e) = send_file(conf, job, &key.mode, document, caption, file, &meta).await {
+      log::warn!("Could not resend cached {:?}: {}", key, e);
+      // file_id is refused, other errors may pass
+      if telegram::telegram_error(&e).is_some_and(|x| x.is_bad_request()) {
+        state.forget_media(key).await;
+      }
+      return false;
+    }
+  }
+  true
+}
+
+/// Remove status message and reset one-time settings after delivery
+async fn finish_download(conf: &Config, state: &State, job: &Job) -> Result<()> {
+  telegram::delete_message(
+    &conf.bot_url(), job.chat_id, job.message_id).await?;
+  state.set_cut(job.settings_id, None).await;
+  Ok(())
+}
+
+/// Write tags and cover art of [video] into audio [files], return them with the cover.
+/// Cover is left out of files it pushes over [Config::max_filesize],
+/// untagged file is kept on failure.
+async fn tag_audio(conf: &Config, video: &ytdlp::Video, url: &url::Url, files: Vec<String>) -> (Vec<String>, Option<String>) {
+  let first = match files.first() {
+    Some(x) => x.clone(),
+    None => return (files, None),
+  };
+  let cover = match &video.thumbnail {
+    Some(thumbnail) => ffmpeg::cover(thumbnail, &first).await
+      .map_err(|e| log::warn!("Could not make cover of {}: {}", first, e))
+      .ok(),
+    None => None,
+  };
+  let fits = |file: &String| std::fs::metadata(file)
+    .is_ok_and(|x| x.len() as i64 <= conf.max_filesize);
+  let parts = files.len();
+  let mut tagged = vec![];
+  for (i, file) in files.into_iter().enumerate() {
+    let tags = ffmpeg::Tags {
+      title: Some(video.track.clone().unwrap_or(video.title.clone())),
+      artist: video.artist.clone().or(video.uploader.clone()),
+      album: video.album.clone().or(video.playlist_title.clone()),
+      date: video.date(),
+      track: (parts > 1).then(|| format!("{}/{}", i + 1, parts)),
+      url: Some(video.webpage_url.clone().unwrap_or(url.to_string())),
+    };
+    let mut res = ffmpeg::tag(&file, &tags, cover.as_deref()).await;
+    if cover.is_some() && res.as_ref().is_ok_and(|x| !fits(x)) {
+      res = ffmpeg::tag(&file, &tags, None).await;
+    }
+    match res {
+      Ok(x) if fits(&x) || !fits(&file) => tagged.push(x),
+      Ok(_) => tagged.push(file),
+      Err(e) => {
+        log::warn!("Could not tag {}: {}", file, e);
+        tagged.push(file);
+      },
+    }
+  }
+  (tagged, cover)
+}
+
+/// Download only [cut] segments of video, one file per segment unless joined
+async fn download_sections(conf: &Config, job: &Job, filename: &String, format_id: Option<String>,
+                           cut: &Cut, accurate: bool, header: &str) -> Result<Vec<String>> {
+  let Job {chat_id, url, message_id, ..} = job.clone();
+  let mut segments = vec![];
+  for (i, segment) in cut.segments.iter().enumerate() {
+    // dot keeps .cut1. from matching .cut10.
+    let name = format!("{}.cut{}.", filename, i);
+    let header = if cut.segments.len() > 1 {
+      format!("{}\nSegment {} ({}/{})", header, segment, i + 1, cut.segments.len())
+    } else {
+      header.to_string()
+    };
+    let (tx, rx) = progress::channel();
+    let (res, ()) = tokio::join!(
+      ytdlp::download(url.clone(), format!("{}/{}%(ext)s", conf.download_dir, name),
+                      format_id.clone(), Some(segment), accurate, tx),
+      progress::report(conf, chat_id, message_id, &header, rx));
+    res?;
+    segments.push(utils::find_file_pat(&conf.download_dir, &name)?);
+  }
+  let first = segments.first().cloned().ok_or(anyhow!("Nothing to download"))?;
+  join_segments(cut, segments, &first).await
+}
+
+/// Join [segments] of [filename] into one file when [cut] asks for it
+async fn join_segments(cut: &Cut, segments: Vec<String>, filename: &String) -> Result<Vec<String>> {
+  if cut.join && segments.len() > 1 {
+    Ok(vec![ffmpeg::join(&segments, filename).await?])
+  } else {
+    Ok(segments)
+  }
+}
 
 
 // Handle download command
-async fn download_url_inner(conf: &Config, state: &State, chat_id: i64, url: url::Url, message_id: i64) -> Result<()> {
-  let video = ytdlp::describe(url.clone()).await?;
+async fn download_url_inner(conf: &Config, state: &State, job: &Job, format_id: Option<String>) -> Result<()> {
+  let Job {chat_id, settings_id, url, message_id, ..} = job.clone();
+  let userconf = state.get_userconfig(settings_id).await;
+  let request = RequestKey {url: url.to_string(), format_id: format_id.clone(), userconf: userconf.clone()};
+  // same URL with same settings was delivered already
+  if let Some((key, files)) = state.cached_request(&request).await {
+    if send_cached(conf, state, job, &key, files).await {
+      return finish_download(conf, state, job).await;
+    }
+  }
+  // plain channel URL would list every video of it
+  let items = format!("1-{}", conf.max_playlist);
+  let video = match ytdlp::describe_any(url.clone(), Some(items)).await? {
+    ytdlp::Description::Video(video) => video,
+    ytdlp::Description::Playlist(playlist) =>
+      return offer_playlist(conf, state, chat_id, message_id, playlist).await,
+  };
   // log::debug!("{}", video);
-  let userconf = state.get_userconfig(chat_id).await;
-  let ChosenFormat {format_id, ext, vcodec, acodec} =
-    choose_format(conf, &userconf, &video)?;
+  let cut = match userconf.cut.clone() {
+    Some(cut) => {
+      cut.validate(video.duration)?;
+      Some(cut)
+    },
+    // link to moment of video, unless user asked for other part; ignored if past the end
+    None => CutInterval::from_url(&url)
+      .map(|x| Cut {segments: vec![x], join: false})
+      .filter(|x| x.validate(video.duration).is_ok()),
+  };
+  let ChosenFormat {format_id, ext, vcodec, acodec, oversize} = match format_id {
+    Some(format_id) => find_format(&video, &format_id)?,
+    None => choose_format(conf, &userconf, &video, cut.as_ref())?,
+  };
+  let convert_audio = userconf.mode == Mode::Audio && userconf.audio_format != AudioFormat::Original;
+  let key = MediaKey {
+    video_id: format!("{}:{}", video.extractor_key, video.id),
+    format_id: format_id.clone().unwrap_or_default(),
+    cut: cut.clone(),
+    cut_mode: cut.as_ref().map(|_| userconf.cut_mode.clone()),
+    mode: userconf.mode.clone(),
+    as_file: userconf.as_file,
+    audio: convert_audio.then(|| (userconf.audio_format.clone(), userconf.abitrate)),
+  };
+  // same media was requested by other URL
+  if let Some(files) = state.cached_media(&key).await {
+    if send_cached(conf, state, job, &key, files).await {
+      state.cache_files(request, key, None).await;
+      return finish_download(conf, state, job).await;
+    }
+  }
+  let header = format!("Downloading {} with format {}, video codec {:?}, audio codec {:?}...", 
+                       url, ext, vcodec.as_deref().unwrap_or_default(), acodec.as_deref().unwrap_or_default());
   telegram::edit_message_text(
-    &conf.telegram_token, chat_id, message_id,
-    format!("Downloading {} with format {}, video codec {:?}, audio codec {:?}...", 
-            url, ext, vcodec.as_deref().unwrap_or_default(), acodec.as_deref().unwrap_or_default())).await?;
+    &conf.bot_url(), chat_id, message_id, header.clone()).await?;
   
   // let filename = uuid::Uuid::new_v4().to_string();
-  let filename = format!("{}_{}", chat_id, &video.id);
+  let filename = format!("{}{}", job.file_prefix(), &video.id);
   let filename_tpl = format!("{}/{}.%(ext)s", conf.download_dir, filename);
-  ytdlp::download(url.clone(), filename_tpl, format_id).await?;
-  let full_filename = utils::find_file_pat(&conf.download_dir, &filename)?;
-  let full_filename =
-    if let Some(cut_interval) = userconf.cut_interval {
-      ffmpeg::cut(&full_filename, cut_interval).await?
+  let accurate = userconf.cut_mode == CutMode::Accurate;
+  let files = match &cut {
+    Some(cut) => {
+      match download_sections(conf, job, &filename, format_id.clone(), cut, accurate, &header).await {
+        Ok(files) => Some(files),
+        // some sites can not be downloaded partially, cut after full download then
+        Err(e) if e.downcast_ref::<ytdlp::SectionsUnsupported>().is_some() => {
+          log::warn!("Downloading sections of {} failed: {}", url, e);
+          utils::remove_files_pat(&conf.download_dir, &format!("{}.cut", filename));
+          None
+        },
+        Err(e) => return Err(e),
+      }
+    },
+    None => None,
+  };
+  let files = match files {
+    Some(files) => files,
+    None => {
+      let (tx, rx) = progress::channel();
+      let (res, ()) = tokio::join!(
+        ytdlp::download(url.clone(), filename_tpl, format_id, None, false, tx),
+        progress::report(conf, chat_id, message_id, &header, rx));
+      res?;
+      let full_filename = utils::find_file_pat(&conf.download_dir, &filename)?;
+      match &cut {
+        Some(cut) => {
+          telegram::edit_message_text(
+            &conf.bot_url(), chat_id, message_id,
+            format!("Cutting {} of {}...", cut, video.title)).await?;
+          let mut segments = vec![];
+          for (i, segment) in cut.segments.iter().enumerate() {
+            segments.push(ffmpeg::cut(&full_filename, segment, i, accurate, conf.max_filesize).await?);
+          }
+          join_segments(cut, segments, &full_filename).await?
+        },
+        None => vec![full_filename],
+      }
+    },
+  };
+  let files = if convert_audio {
+    telegram::edit_message_text(
+      &conf.bot_url(), chat_id, message_id,
+      format!("Converting {} to {:?}...", video.title, userconf.audio_format)).await?;
+    let mut converted = vec![];
+    for file in files {
+      converted.push(ffmpeg::convert_audio(&file, &userconf.audio_format, userconf.abitrate).await?);
+    }
+    converted
+  } else {
+    files
+  };
+  let mut parts = vec![];
+  for (i, file) in files.into_iter().enumerate() {
+    let size = std::fs::metadata(&file)?.len() as i64;
+    if size <= conf.max_filesize {
+      parts.push(file);
+      continue;
+    }
+    // cut segments differ from whole video
+    let duration = match &cut {
+      Some(cut) => {
+        let expected = if cut.join {
+          cut.duration(video.duration)
+        } else {
+          cut.segments[i].duration(video.duration)
+        };
+        ffmpeg::probe(&file).await.ok().and_then(|x| x.duration).or(expected)
+      },
+      None => video.duration,
+    };
+    match oversize {
+      Some(Oversize::Reencode) => {
+        let duration = duration.ok_or(anyhow!("Unknown duration, can not re-encode"))?;
+        telegram::edit_message_text(
+          &conf.bot_url(), chat_id, message_id,
+          format!("Re-encoding {} to fit into {}...", video.title,
+                  utils::human_size(conf.max_filesize as f64))).await?;
+        parts.push(ffmpeg::fit(&file, duration, conf.max_filesize).await?);
+      },
+      Some(Oversize::Split) => {
+        let duration = duration.ok_or(anyhow!("Unknown duration, can not split"))?;
+        telegram::edit_message_text(
+          &conf.bot_url(), chat_id, message_id,
+          format!("Splitting {} into parts...", video.title)).await?;
+        parts.extend(ffmpeg::split(&file, duration, conf.max_filesize).await?);
+      },
+      _ => parts.push(file),
+    }
+  }
+  let (files, cover) = match userconf.mode {
+    Mode::Audio => tag_audio(conf, &video, &url, parts).await,
+    Mode::Video => (parts, None),
+  };
+  let parts = files.len();
+  // None if some file_id is unknown
+  let mut cached = Some(vec![]);
+  for (i, file) in files.into_iter().enumerate() {
+    let caption = if parts > 1 {
+      format!("{} ({}/{})", video.title, i + 1, parts)
     } else {
-      full_filename
+      video.title.clone()
     };
-  match userconf.mode {
-    Mode::Video =>
-      telegram::send_video(&conf.telegram_token, chat_id, video.title.clone(), full_filename.clone()).await?,
-    Mode::Audio =>
-      telegram::send_audio(&conf.telegram_token, chat_id, video.title.clone(), full_filename.clone()).await?,
+    let meta = media_meta(&video, &userconf.mode, &file, cover.as_deref()).await;
+    let file = if conf.telegram_local { InputFile::Local(file) } else { InputFile::Upload(file) };
+    let sent = send_file(conf, job, &userconf.mode, userconf.as_file, caption.clone(), file, &meta).await?;
+    cached = cached.zip(sent).map(|(mut cached, SentFile {file_id, document})| {
+      cached.push(CachedFile {file_id, caption, document});
+      cached
+    });
+  }
+  state.cache_files(request, key, cached).await;
+  finish_download(conf, state, job).await
+}
+
+// Offer formats of URL to pick from
+async fn pick_format_inner(conf: &Config, state: &State, job: &Job) -> Result<()> {
+  let Job {chat_id, settings_id, url, message_id, ..} = job.clone();
+  let video = ytdlp::describe(url.clone()).await?;
+  let userconf = state.get_userconfig(settings_id).await;
+  let formats = format_chooser::candidates(conf, &userconf, &video);
+  if formats.is_empty() {
+    return Err(anyhow!("Sorry, no formats small enough to send"));
+  }
+  state.add_pending(chat_id, message_id, Pending::Pick(url)).await;
+  telegram::edit_message_text_markup(
+    &conf.bot_url(), chat_id, message_id,
+    format!("Pick format of {}:", video.title),
+    Some(format_picker::keyboard(&formats))).await?;
+
+  Ok(())
+}
+
+/// Ask user to confirm download of playlist entries
+async fn offer_playlist(conf: &Config, state: &State, chat_id: i64, message_id: i64, playlist: ytdlp::Playlist) -> Result<()> {
+  log::info!("{}", playlist);
+  let urls : Vec<_> = playlist.entries.iter()
+    .filter_map(|x| x.get_url())
+    .take(conf.max_playlist)
+    .collect();
+  if urls.is_empty() {
+    return Err(anyhow!("Playlist {} has no entries to download", playlist.title));
+  }
+  let total = playlist.playlist_count.unwrap_or(playlist.entries.len() as u64);
+  let text = if total as usize > urls.len() {
+    format!("{} has {} entries, download first {}?", playlist.title, total, urls.len())
+  } else {
+    format!("{} has {} entries, download all?", playlist.title, urls.len())
   };
-  for file in utils::find_files_pat(&conf.download_dir, &filename)? {
-    std::fs::remove_file(file)?;
+  let keyboard = playlist_prompt::keyboard(urls.len());
+  state.add_pending(chat_id, message_id, Pending::Playlist(playlist.title, urls)).await;
+  telegram::edit_message_text_markup(
+    &conf.bot_url(), chat_id, message_id, text, Some(keyboard)).await?;
+
+  Ok(())
+}
+
+// Describe playlist and offer to download its entries
+async fn playlist_inner(conf: &Config, state: &State, job: &Job, items: Option<String>) -> Result<()> {
+  let Job {chat_id, url, message_id, ..} = job.clone();
+  // do not list whole channel just to download some first entries
+  let items = items.unwrap_or(format!("1-{}", conf.max_playlist));
+  match ytdlp::describe_any(url, Some(items)).await? {
+    ytdlp::Description::Playlist(playlist) =>
+      offer_playlist(conf, state, chat_id, message_id, playlist).await,
+    ytdlp::Description::Video(_) => Err(anyhow!("This is not a playlist, send it without command")),
   }
-  telegram::delete_message(
-    &conf.telegram_token, chat_id, message_id).await?;
-  state.set_cut_inteval(chat_id, None).await;
+}
+
+/// Key of user config for sender of [msg]
+fn settings_id(conf: &Config, msg: &IncomeMessage) -> i64 {
+  if conf.settings_per_user { msg.user_id } else { msg.chat_id }
+}
+
+/// Request message to reply to, button presses have none
+fn reply_to(msg: &IncomeMessage) -> Option<i64> {
+  match msg.callback_id {
+    Some(_) => None,
+    None => Some(msg.message_id),
+  }
+}
+
+/// Answer in chat of [msg], as reply to it
+async fn reply(conf: &Config, msg: &IncomeMessage, text: String) -> Result<()> {
+  telegram::send_reply(&conf.bot_url(), msg.chat_id, reply_to(msg), text).await?;
   Ok(())
 }
 
-/// Download URL, reporting error back to chat
-async fn download_url(conf: &Config, state: &State, msg: &IncomeMessage, url: url::Url) -> Result<()> {
-  let &IncomeMessage {chat_id, ..} = msg;
-  let response = telegram::send_message(
-    &conf.telegram_token, chat_id,
-    format!("Downloading {}...", url)).await?;
+/// Message as if it was sent to bot directly, None if it is not for bot.
+/// In groups bot reacts to commands, mentions and replies to its messages.
+fn addressed(conf: &Config, msg: &IncomeMessage) -> Option<IncomeMessage> {
+  let mention = format!("@{}", conf.bot_username);
+  let mut words = msg.text.split_whitespace();
+  if let Some(command) = words.next().filter(|x| x.starts_with('/')) {
+    let command = match command.split_once('@') {
+      Some((command, bot)) if bot.eq_ignore_ascii_case(&conf.bot_username) => command,
+      // command for other bot
+      Some(_) => return None,
+      None => command,
+    };
+    let text = std::iter::once(command).chain(words).join(" ");
+    return Some(IncomeMessage {text, .. msg.clone()});
+  }
+  if msg.private {
+    return Some(msg.clone());
+  }
+  let mentioned = msg.text.split_whitespace().any(|x| x.eq_ignore_ascii_case(&mention));
+  if !mentioned && msg.reply_to_user != Some(conf.bot_id) {
+    return None;
+  }
+  let text = msg.text.split_whitespace()
+    .filter(|x| !x.eq_ignore_ascii_case(&mention))
+    .join(" ");
+  // "@bot" in reply to message with link
+  let urls = if msg.urls.is_empty() { msg.reply_urls.clone() } else { msg.urls.clone() };
+  Some(IncomeMessage {text, urls, .. msg.clone()})
+}
+
+/// Queue job for URL, reporting rejection back to chat
+async fn queue_job(conf: &Config, queue: &Arc<JobQueue>, msg: &IncomeMessage, url: url::Url, kind: JobKind) -> Result<()> {
+  let chat_id = msg.chat_id;
+  let reply_to = reply_to(msg);
+  let response = telegram::send_reply(
+    &conf.bot_url(), chat_id, reply_to,
+    format!("Queued {}...", url)).await?;
   let result = response.result.ok_or(anyhow!(response.description))?;
   let message_id = result.message_id;
-  let res = download_url_inner(conf, state, chat_id, url, message_id).await;
+  let settings_id = settings_id(conf, msg);
+  if let Err(e) = queue.submit(Job {chat_id, user_id: msg.user_id, settings_id, url, message_id, reply_to, kind}) {
+    telegram::edit_message_text(&conf.bot_url(), chat_id, message_id, e.to_string()).await?;
+  };
+
+  Ok(())
+}
+
+/// Queue downloads of several URLs at once (all or none), reporting rejection back to chat
+async fn queue_downloads(conf: &Config, queue: &Arc<JobQueue>, msg: &IncomeMessage, urls: Vec<url::Url>) -> Result<()> {
+  let chat_id = msg.chat_id;
+  if let Err(e) = queue.check(chat_id, msg.user_id, urls.len()) {
+    reply(conf, msg, e.to_string()).await?;
+    return Ok(());
+  }
+  let settings_id = settings_id(conf, msg);
+  let reply_to = reply_to(msg);
+  let mut jobs = vec![];
+  for url in urls {
+    let response = telegram::send_reply(
+      &conf.bot_url(), chat_id, reply_to,
+      format!("Queued {}...", url)).await?;
+    let result = response.result.ok_or(anyhow!(response.description))?;
+    let kind = JobKind::Download {format_id: None};
+    jobs.push(Job {chat_id, user_id: msg.user_id, settings_id, url, message_id: result.message_id, reply_to, kind});
+  }
+  if let Err(e) = queue.submit_batch(jobs.clone()) {
+    for job in jobs {
+      telegram::edit_message_text(&conf.bot_url(), chat_id, job.message_id, e.to_string()).await?;
+    }
+  }
+
+  Ok(())
+}
+
+/// Run queued job, reporting error back to chat
+pub async fn run_job(conf: &Config, state: &State, job: &Job) -> Result<()> {
+  let Job {chat_id, url, message_id, kind, ..} = job.clone();
+  let run = async {
+    match kind {
+      JobKind::Download {format_id} => {
+        telegram::edit_message_text(
+          &conf.bot_url(), chat_id, message_id,
+          format!("Downloading {}...", url)).await?;
+        download_url_inner(conf, state, job, format_id).await
+      },
+      JobKind::PickFormat => {
+        telegram::edit_message_text(
+          &conf.bot_url(), chat_id, message_id,
+          format!("Looking up formats of {}...", url)).await?;
+        pick_format_inner(conf, state, job).await
+      },
+      JobKind::Playlist {items} => {
+        telegram::edit_message_text(
+          &conf.bot_url(), chat_id, message_id,
+          format!("Looking up entries of {}...", url)).await?;
+        playlist_inner(conf, state, job, items).await
+      },
+    }
+  };
+  let res = run.await;
+  // whatever step failed, its files are of no use
+  utils::remove_files_pat(&conf.download_dir, &job.file_prefix());
   if let Err(e) = &res {
-    telegram::edit_message_text(&conf.telegram_token, chat_id, message_id, e.to_string()).await?;
+    telegram::edit_message_text(&conf.bot_url(), chat_id, message_id, e.to_string()).await?;
   };
-  
+
+  Ok(())
+}
+
+/// Clean up after cancelled job
+pub async fn job_cancelled(conf: &Config, job: &Job) -> Result<()> {
+  let &Job {chat_id, message_id, ..} = job;
+  utils::remove_files_pat(&conf.download_dir, &job.file_prefix());
+  telegram::edit_message_text(
+    &conf.bot_url(), chat_id, message_id,
+    format!("Job for {} cancelled", job.url)).await?;
+
   Ok(())
 }
 
+/// Handle format picker button press, return callback answer
+async fn react_pick(conf: &Config, state: &State, queue: &Arc<JobQueue>, msg: &IncomeMessage, data: &str) -> Result<Option<String>> {
+  let &IncomeMessage {chat_id, message_id, ..} = msg;
+  let url = match state.take_pending(chat_id, message_id).await {
+    Some(Pending::Pick(url)) => url,
+    _ => {
+      telegram::edit_message_text(
+        &conf.bot_url(), chat_id, message_id,
+        "Format list has expired, send the link again".to_string()).await?;
+      return Ok(None);
+    },
+  };
+  if data == format_picker::CANCEL {
+    telegram::delete_message(&conf.bot_url(), chat_id, message_id).await?;
+    return Ok(None);
+  }
+  // replacing text drops the keyboard
+  telegram::edit_message_text(
+    &conf.bot_url(), chat_id, message_id,
+    format!("Queued {} with format {}...", url, data)).await?;
+  let kind = JobKind::Download {format_id: Some(data.to_string())};
+  let settings_id = settings_id(conf, msg);
+  if let Err(e) = queue.submit(Job {chat_id, user_id: msg.user_id, settings_id, url, message_id, reply_to: None, kind}) {
+    telegram::edit_message_text(&conf.bot_url(), chat_id, message_id, e.to_string()).await?;
+  }
+
+  Ok(None)
+}
+
+/// Handle playlist confirmation button press, return callback answer
+async fn react_playlist(conf: &Config, state: &State, queue: &Arc<JobQueue>, msg: &IncomeMessage, data: &str) -> Result<Option<String>> {
+  let &IncomeMessage {chat_id, message_id, ..} = msg;
+  let (title, urls) = match state.take_pending(chat_id, message_id).await {
+    Some(Pending::Playlist(title, urls)) => (title, urls),
+    _ => {
+      telegram::edit_message_text(
+        &conf.bot_url(), chat_id, message_id,
+        "Playlist prompt has expired, send the link again".to_string()).await?;
+      return Ok(None);
+    },
+  };
+  if data != playlist_prompt::CONFIRM {
+    telegram::delete_message(&conf.bot_url(), chat_id, message_id).await?;
+    return Ok(None);
+  }
+  if let Err(e) = queue.check(chat_id, msg.user_id, urls.len()) {
+    telegram::edit_message_text(&conf.bot_url(), chat_id, message_id, e.to_string()).await?;
+    return Ok(None);
+  }
+  telegram::edit_message_text(
+    &conf.bot_url(), chat_id, message_id,
+    format!("Queued {} entries of {}", urls.len(), title)).await?;
+  queue_downloads(conf, queue, msg, urls).await?;
+
+  Ok(None)
+}
+
+/// Handle inline keyboard button press
+async fn react_callback(conf: &Config, state: &State, queue: &Arc<JobQueue>, msg: &IncomeMessage, callback_id: String) -> Result<()> {
+  let IncomeMessage {chat_id, message_id, text, ..} = msg.clone();
+  if let Some(data) = text.strip_prefix(format_picker::PREFIX) {
+    let answer = react_pick(conf, state, queue, msg, data).await?;
+    return telegram::answer_callback_query(&conf.bot_url(), callback_id, answer).await;
+  }
+  if let Some(data) = text.strip_prefix(playlist_prompt::PREFIX) {
+    let answer = react_playlist(conf, state, queue, msg, data).await?;
+    return telegram::answer_callback_query(&conf.bot_url(), callback_id, answer).await;
+  }
+  let answer = match text.strip_prefix(settings_menu::PREFIX) {
+    Some("close") => {
+      telegram::delete_message(&conf.bot_url(), chat_id, message_id).await?;
+      None
+    },
+    Some(data) => {
+      let mut known = true;
+      let userconf = state.update_userconfig(settings_id(conf, msg), |userconf| {
+        settings_menu::apply(userconf.clone(), data).unwrap_or_else(|| {
+          known = false;
+          userconf
+        })
+      }).await;
+      if known {
+        telegram::edit_message_reply_markup(
+          &conf.bot_url(), chat_id, message_id,
+          settings_menu::keyboard(&userconf)).await?;
+        None
+      } else {
+        Some("Unknown setting".to_string())
+      }
+    },
+    None => Some("Unknown button".to_string()),
+  };
+  telegram::answer_callback_query(&conf.bot_url(), callback_id, answer).await
+}
 
 // Dispatch commands
-pub async fn react(conf: &Config, state: &State, msg: &IncomeMessage) -> Result<()> {
+pub async fn react(conf: &Config, state: &State, queue: &Arc<JobQueue>, msg: &IncomeMessage) -> Result<()> {
   log::info!("command {}", msg);
-  match url::Url::parse(&msg.text) {
-    Ok(url) => {
-      let res = download_url(conf, state, msg, url).await;
+  if let Some(callback_id) = &msg.callback_id {
+    return react_callback(conf, state, queue, msg, callback_id.clone()).await;
+  }
+  // links in commands are arguments
+  let urls = if msg.text.starts_with('/') { vec![] } else { msg.urls.clone() };
+  match urls.as_slice() {
+    [url] => {
+      let kind = JobKind::Download {format_id: None};
+      let res = queue_job(conf, queue, msg, url.clone(), kind).await;
       match res {
         Ok(()) => (),
         Err(e) => log::error!("Error: {:?}", e),
@@ -78,93 +677,196 @@ pub async fn react(conf: &Config, state: &State, msg: &IncomeMessage) -> Result<
           
       Ok(())
     },
-    Err(_) => {
+    [_, ..] => {
+      let res = queue_downloads(conf, queue, msg, urls).await;
+      match res {
+        Ok(()) => (),
+        Err(e) => log::error!("Error: {:?}", e),
+      }
+
+      Ok(())
+    },
+    [] => {
       let &IncomeMessage {chat_id, ..} = msg;
+      let settings_id = settings_id(conf, msg);
       let words : Vec<_> = msg.text.split_whitespace()
       // .map(|x| x.to_string())
         .collect();
       match words.as_slice() {
         ["/st", ..] => {
-          let userconf = state.get_userconfig(chat_id).await;
-          telegram::send_message(
-            &conf.telegram_token, chat_id,
-            format!("Current user config is:\n{}", userconf)).await?;
+          let userconf = state.get_userconfig(settings_id).await;
+          reply(conf, msg,
+                format!("Current user config is:\n{}downloads in progress: {}\n",
+                        userconf, queue.user_jobs(chat_id, msg.user_id))).await?;
+          Ok(())
+        },
+        ["/settings", ..] => {
+          let userconf = state.get_userconfig(settings_id).await;
+          telegram::send_message_markup(
+            &conf.bot_url(), chat_id, "Settings".to_string(), reply_to(msg),
+            Some(settings_menu::keyboard(&userconf))).await?;
+          Ok(())
+        },
+        ["/pick", url] => {
+          match url::Url::parse(url) {
+            Ok(url) => queue_job(conf, queue, msg, url, JobKind::PickFormat).await?,
+            Err(e) => {
+              reply(conf, msg, format!("Could not parse URL: {}", e)).await?;
+            },
+          };
+          Ok(())
+        },
+        ["/playlist", url, items @ ..] => {
+          let items = match items {
+            [] => Ok(None),
+            [items] if items.chars().all(|c| c.is_ascii_digit() || ",-:".contains(c)) =>
+              Ok(Some(items.to_string())),
+            _ => Err("Items should look like 1-10 or 1,3,5".to_string()),
+          };
+          let res = items.and_then(
+            |items| url::Url::parse(url).map(|url| (url, items))
+              .map_err(|e| format!("Could not parse URL: {}", e)));
+          match res {
+            Ok((url, items)) =>
+              queue_job(conf, queue, msg, url, JobKind::Playlist {items}).await?,
+            Err(e) => {
+              reply(conf, msg, e).await?;
+            },
+          };
+          Ok(())
+        },
+        ["/cancel", ..] => {
+          let text = match queue.cancel(chat_id, msg.user_id) {
+            0 => "Nothing to cancel".to_string(),
+            n => format!("Cancelling {} downloads", n),
+          };
+          reply(conf, msg, text).await?;
           Ok(())
         },
         ["/audio", ..] => {
-          state.set_mode(chat_id, Mode::Audio).await;
-          telegram::send_message(
-            &conf.telegram_token, chat_id,
-            "Switched to audio download".to_string()).await?;
+          state.set_mode(settings_id, Mode::Audio).await;
+          reply(conf, msg, "Switched to audio download".to_string()).await?;
           Ok(())
         },
         ["/video", ..] => {
-          state.set_mode(chat_id, Mode::Video).await;
-          telegram::send_message(
-            &conf.telegram_token, chat_id,
-            "Switched to video download".to_string()).await?;
+          state.set_mode(settings_id, Mode::Video).await;
+          reply(conf, msg, "Switched to video download".to_string()).await?;
           Ok(())
         },
         ["/video_quality_high", ..] => {
-          state.set_video_quality(chat_id, Quality::High).await;
-          telegram::send_message(
-            &conf.telegram_token, chat_id,
-            "Set video quality to High".to_string()).await?;
+          state.set_video_quality(settings_id, Quality::High).await;
+          reply(conf, msg, "Set video quality to High".to_string()).await?;
           Ok(())
         },
         ["/video_quality_low", ..] => {
-          state.set_video_quality(chat_id, Quality::Low).await;
-          telegram::send_message(
-            &conf.telegram_token, chat_id,
-            "Set video quality to Low".to_string()).await?;
+          state.set_video_quality(settings_id, Quality::Low).await;
+          reply(conf, msg, "Set video quality to Low".to_string()).await?;
           Ok(())
         },
         ["/video_quality_awful", ..] => {
-          state.set_video_quality(chat_id, Quality::Awful).await;
-          telegram::send_message(
-            &conf.telegram_token, chat_id,
-            "Set video quality to Awful".to_string()).await?;
+          state.set_video_quality(settings_id, Quality::Awful).await;
+          reply(conf, msg, "Set video quality to Awful".to_string()).await?;
           Ok(())
         },
         ["/audio_quality_high", ..] => {
-          state.set_audio_quality(chat_id, Quality::High).await;
-          telegram::send_message(
-            &conf.telegram_token, chat_id,
-            "Set audio quality to High".to_string()).await?;
+          state.set_audio_quality(settings_id, Quality::High).await;
+          reply(conf, msg, "Set audio quality to High".to_string()).await?;
           Ok(())
         },
         ["/audio_quality_low", ..] => {
-          state.set_audio_quality(chat_id, Quality::Low).await;
-          telegram::send_message(
-            &conf.telegram_token, chat_id,
-            "Set audio quality to Low".to_string()).await?;
+          state.set_audio_quality(settings_id, Quality::Low).await;
+          reply(conf, msg, "Set audio quality to Low".to_string()).await?;
           Ok(())
         },
         ["/vcodec_exclude", vcodecs @ ..] => {
           let vcodecs = vcodecs.iter().map(|x| x.to_string()).collect();
           let UserConfig {vcodec_exclude, .. } =
-            state.set_vcodec_exclude(chat_id, vcodecs).await;
-          let msg = format!("Set video codecs excludes to {}",
+            state.set_vcodec_exclude(settings_id, vcodecs).await;
+          let text = format!("Set video codecs excludes to {}",
                             vcodec_exclude.join(" "));
-          telegram::send_message(
-            &conf.telegram_token, chat_id, msg).await?;
+          reply(conf, msg, text).await?;
           Ok(())
         },
-        ["/cut_interval", start, end] => {
-          let cut_interval = CutInterval::parse(start, end)?;
-          let UserConfig {cut_interval, .. } =
-            state.set_cut_inteval(chat_id, Some(cut_interval)).await;
-          let msg = format!("Set video cut interval to {:?}",
-                            cut_interval);
-          telegram::send_message(
-            &conf.telegram_token, chat_id, msg).await?;
+        ["/as_file", ..] => {
+          state.set_as_file(settings_id, true).await;
+          reply(conf, msg, "Files will be sent as documents".to_string()).await?;
+          Ok(())
+        },
+        ["/as_media", ..] => {
+          state.set_as_file(settings_id, false).await;
+          reply(conf, msg, "Files will be sent as playable media".to_string()).await?;
+          Ok(())
+        },
+        ["/audio_format", format, abitrate @ ..] => {
+          let parsed = AudioFormat::parse(format)
+            .ok_or(anyhow!("Unknown audio format {}, use mp3, m4a, opus, flac or original", format))
+            .and_then(|audio_format| {
+              let abitrate = abitrate.first()
+                .map(|x| x.trim_end_matches('k').parse::<u32>().ok().filter(|x| (8..=512).contains(x))
+                     .ok_or(anyhow!("Bad bitrate {}, expected kbps like 192", x)))
+                .transpose()?;
+              Ok((audio_format, abitrate))
+            });
+          let (audio_format, abitrate) = match parsed {
+            Ok(x) => x,
+            Err(e) => {
+              reply(conf, msg, e.to_string()).await?;
+              return Ok(());
+            },
+          };
+          let UserConfig {audio_format, abitrate, ..} =
+            state.set_audio_format(settings_id, audio_format, abitrate).await;
+          let text = match audio_format {
+            AudioFormat::Original => "Audio will be sent as downloaded".to_string(),
+            AudioFormat::Flac => "Audio will be converted to Flac".to_string(),
+            _ => format!("Audio will be converted to {:?} {}kbps", audio_format, abitrate),
+          };
+          reply(conf, msg, text).await?;
+          Ok(())
+        },
+        ["/oversize_reencode", ..] => {
+          state.set_oversize(settings_id, Oversize::Reencode).await;
+          reply(conf, msg, "Too big videos will be re-encoded to fit".to_string()).await?;
+          Ok(())
+        },
+        ["/oversize_split", ..] => {
+          state.set_oversize(settings_id, Oversize::Split).await;
+          reply(conf, msg, "Too big files will be sent in several parts".to_string()).await?;
+          Ok(())
+        },
+        ["/oversize_reject", ..] => {
+          state.set_oversize(settings_id, Oversize::Reject).await;
+          reply(conf, msg, "Too big videos will be rejected".to_string()).await?;
+          Ok(())
+        },
+        ["/cut_fast", ..] => {
+          state.set_cut_mode(settings_id, CutMode::Fast).await;
+          reply(conf, msg, "Cuts will start at nearest keyframe".to_string()).await?;
+          Ok(())
+        },
+        ["/cut_accurate", ..] => {
+          state.set_cut_mode(settings_id, CutMode::Accurate).await;
+          reply(conf, msg, "Short cuts will be re-encoded to start exactly".to_string()).await?;
+          Ok(())
+        },
+        ["/cut_interval", args @ ..] => {
+          let cut = match Cut::parse(args) {
+            Ok(cut) => cut,
+            Err(e) => {
+              reply(conf, msg, e.to_string()).await?;
+              return Ok(());
+            },
+          };
+          let UserConfig {cut, .. } =
+            state.set_cut(settings_id, Some(cut)).await;
+          let text = format!("Next download will be cut to {}",
+                             cut.map(|x| x.to_string()).unwrap_or_default());
+          reply(conf, msg, text).await?;
           Ok(())
         },
 
         _ =>  {
-          telegram::send_message(
-            &conf.telegram_token, chat_id,
-            "Unknown command".to_string()).await?;
+          reply(conf, msg, "Unknown command".to_string()).await?;
           Ok(())
         }
       }
@@ -174,24 +876,51 @@ pub async fn react(conf: &Config, state: &State, msg: &IncomeMessage) -> Result<
 
 
 // Throttle and call dispatcher
-pub async fn react_messages(conf: &Config, state: &State, messages: Vec<IncomeMessage>) -> Result<()> {
-  let messages = messages.iter()
-    .sorted_by_key(|x| &x.username)
-    .group_by(|x| &x.username);
-  for (username, group) in &messages {
-    // if group.collect().
-    let group = group.collect_vec();
-    match group[..] {
-      [] => continue,
-      [msg] => react(conf, state, msg).await?,
-      [IncomeMessage {chat_id, ..}, ..] => {
-        log::warn!("User {} Too many requests", username);
-        telegram::send_message(
-          &conf.telegram_token, *chat_id,
-          "Too many requests".to_string()).await?;
-      }
+/// Commands not counted by rate limiter
+const UNLIMITED_COMMANDS: [&str; 2] = ["/cancel", "/st"];
+
+/// Handle messages of one batch, error of one does not stop others
+pub async fn react_messages(conf: &Arc<Config>, state: &Arc<State>, queue: &Arc<JobQueue>, limiter: &Arc<RateLimiter>, messages: Vec<IncomeMessage>) {
+  for msg in messages {
+    if let Err(e) = react_message(conf, state, queue, limiter, msg).await {
+      log::error!("Error: {:?}", e);
     }
   }
+}
+
+async fn react_message(conf: &Arc<Config>, state: &Arc<State>, queue: &Arc<JobQueue>, limiter: &Arc<RateLimiter>, msg: IncomeMessage) -> Result<()> {
+  // button presses are answered right away
+  if msg.callback_id.is_some() {
+    return react(conf, state, queue, &msg).await;
+  }
+  let msg = match addressed(conf, &msg) {
+    Some(msg) => msg,
+    None => return Ok(()),
+  };
+  // stopping a flood must not wait behind it
+  if msg.text.split_whitespace().next().is_some_and(|x| UNLIMITED_COMMANDS.contains(&x)) {
+    return react(conf, state, queue, &msg).await;
+  }
+  match limiter.admit(msg.user_id) {
+    Admission::Now => react(conf, state, queue, &msg).await?,
+    Admission::Wait {delay, position} => {
+      log::warn!("User {}({}) throttled for {:?}", msg.username, msg.user_id, delay);
+      reply(conf, &msg, format!("Too many requests, you're #{} in queue", position)).await?;
+      let (conf, state, queue, limiter) =
+        (conf.clone(), state.clone(), queue.clone(), limiter.clone());
+      tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        limiter.done_waiting(msg.user_id);
+        if let Err(e) = react(&conf, &state, &queue, &msg).await {
+          log::error!("Error: {:?}", e);
+        }
+      });
+    },
+    Admission::Reject => {
+      log::warn!("User {}({}) Too many requests", msg.username, msg.user_id);
+      reply(conf, &msg, "Too many requests, wait a bit".to_string()).await?;
+    },
+  }
 
   Ok(())
 }
@@ -1,7 +1,61 @@
+use std::net::SocketAddr;
+
+#[derive(Clone)]
+pub struct WebhookConfig {
+  /// Public https url telegram will post updates to
+  pub url: String,
+  /// Local address embedded http server listens on
+  pub listen: SocketAddr,
+  /// Value of X-Telegram-Bot-Api-Secret-Token header
+  pub secret_token: String,
+}
+
 #[derive(Clone)]
 pub struct Config {
   pub max_filesize: i64,
   // pub vcodec_exclude: Vec<String>,
   pub telegram_token: String,
+  /// Bot API server, e.g. https://api.telegram.org or local telegram-bot-api
+  pub telegram_api_url: String,
+  /// Bot API server runs in --local mode: uploads are passed as file:// paths
+  pub telegram_local: bool,
   pub download_dir: String,
+  /// Receive updates via webhook instead of getUpdates polling
+  pub webhook: Option<WebhookConfig>,
+  /// getUpdates long polling timeout, seconds
+  pub poll_timeout: u64,
+  /// Update types telegram should deliver (both polling and webhook)
+  pub allowed_updates: Vec<String>,
+  /// Number of downloads running concurrently
+  pub workers: usize,
+  /// Max queued + running downloads per chat
+  pub max_user_jobs: usize,
+  /// Max queued + running downloads overall
+  pub max_jobs: usize,
+  /// Max playlist entries downloaded per request
+  pub max_playlist: usize,
+  /// Bot's username without "@", commands and mentions in groups are matched against it
+  pub bot_username: String,
+  /// Bot's user id, replies to its messages in groups are for it
+  pub bot_id: i64,
+  /// Keep settings of each group member separately instead of one per chat
+  pub settings_per_user: bool,
+  /// Requests user may send at once
+  pub rate_burst: u32,
+  /// Seconds to get one more request after burst is spent
+  pub rate_refill: u64,
+  /// Max requests of user waiting for rate limit, more are rejected
+  pub rate_queue: usize,
+}
+
+/// Prefix of Bot API method urls
+pub fn bot_url(telegram_api_url: &str, telegram_token: &str) -> String {
+  format!("{}/bot{}", telegram_api_url.trim_end_matches('/'), telegram_token)
+}
+
+impl Config {
+  /// Prefix of Bot API method urls
+  pub fn bot_url(&self) -> String {
+    bot_url(&self.telegram_api_url, &self.telegram_token)
+  }
 }
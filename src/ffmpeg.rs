@@ -1,11 +1,12 @@
 use std::path;
-use anyhow::{Result, Error, anyhow};
+use anyhow::{Result, Error, anyhow, Context};
 use tokio::process::Command;
-use crate::user_state::{CutInterval};
+use crate::user_state::{CutInterval, AudioFormat};
+use crate::utils;
 
 
-/// invent output file name
-fn out_file(filepath: &String) -> Result<String> {
+/// invent output file name: <stem>.<suffix>.<ext>, keeping extension if [new_ext] is None
+fn out_file(filepath: &String, suffix: &str, new_ext: Option<&str>) -> Result<String> {
   log::debug!("ffmpeg out_file({})", filepath);
   let filepath = path::Path::new(filepath);
   let filename = filepath.file_name()
@@ -20,7 +21,7 @@ fn out_file(filepath: &String) -> Result<String> {
     .ok_or(anyhow!("file name is empty"))?
     .to_str()
     .ok_or(anyhow!("file extenstion contains non utf8 characters"))?;
-  let newfilename = format!("{}.cut.{}", file_stem, ext);
+  let newfilename = format!("{}.{}.{}", file_stem, suffix, new_ext.unwrap_or(ext));
   if let Some(parent) = parent {
     let path = parent.join(newfilename);
     let result = path.to_str()
@@ -32,28 +33,407 @@ fn out_file(filepath: &String) -> Result<String> {
 }
 
 
-/// Run ffmpeg to cut part of video.
-pub async fn cut(filename: &String, cut_interval: CutInterval) -> Result<String> {
-  let outfile = out_file(filename)?;
+/// Format milliseconds as ffmpeg time duration
+fn seconds(ms: i64) -> String {
+  format!("{}.{:03}", ms / 1000, ms % 1000)
+}
+
+/// Clips up to this long (seconds) are re-encoded in accurate mode
+const ACCURATE_REENCODE_MAX: f64 = 120.0;
+
+/// Run ffmpeg to cut [index]-th segment of video.
+/// Fast mode copies streams, so video starts at keyframe nearest to cut start.
+/// Accurate mode re-encodes short clips whole, unless result exceeds [max_filesize];
+/// longer clips are copied.
+pub async fn cut(filename: &String, cut_interval: &CutInterval, index: usize, accurate: bool, max_filesize: i64) -> Result<String> {
+  let start = cut_interval.start.map(|x| x as f64 / 1000.0);
+  let end = cut_interval.end.map(|x| x as f64 / 1000.0);
+  let probe = match (accurate, start) {
+    // stream copy is exact for media start
+    (true, Some(_)) => probe(filename).await?,
+    _ => return cut_copy(filename, cut_interval, index).await,
+  };
+  let start = start.unwrap_or(0.0);
+  if probe.vcodec.is_none() {
+    // audio frames are short, copy is accurate enough
+    return cut_copy(filename, cut_interval, index).await;
+  }
+  let length = end.or(probe.duration).map(|x| x - start);
+  if length.is_none_or(|x| x > ACCURATE_REENCODE_MAX) {
+    log::info!("ffmpeg::cut {} is too long to re-encode, copying", cut_interval);
+    return cut_copy(filename, cut_interval, index).await;
+  }
+  let outfile = reencode(filename, &format!("cut{}", index), start, length).await?;
+  if std::fs::metadata(&outfile)?.len() as i64 > max_filesize {
+    // high quality re-encode may be bigger than copied source
+    log::info!("ffmpeg::cut re-encoded {} is too big, copying", cut_interval);
+    std::fs::remove_file(&outfile)?;
+    return cut_copy(filename, cut_interval, index).await;
+  }
+  Ok(outfile)
+}
+
+/// Cut with stream copy, start snaps to keyframe
+async fn cut_copy(filename: &String, cut_interval: &CutInterval, index: usize) -> Result<String> {
+  let outfile = out_file(filename, &format!("cut{}", index), None)?;
   let mut cmd = Command::new("ffmpeg");
-  cmd.arg("-i").arg(filename)
-    .arg("-ss").arg(cut_interval.start.to_string())
-    .arg("-to").arg(cut_interval.end.to_string())
-    .arg("-c:v").arg("copy")
-    .arg("-c:a").arg("copy")
+  cmd.arg("-y").arg("-i").arg(filename);
+  if let Some(start) = cut_interval.start {
+    cmd.arg("-ss").arg(seconds(start));
+  }
+  if let Some(end) = cut_interval.end {
+    cmd.arg("-to").arg(seconds(end));
+  }
+  cmd.arg("-map").arg("0")
+    .arg("-c").arg("copy")
     .arg(&outfile);
-  log::info!("ffmpeg::cut {:?}", &cmd);
-  let output = cmd.output().await?;
+  run(&mut cmd, "cut").await?;
+  
+  Ok(outfile)
+}
+
+/// Re-encode [length] seconds from [start] to h264/aac mp4
+async fn reencode(filename: &String, suffix: &str, start: f64, length: Option<f64>) -> Result<String> {
+  let outfile = out_file(filename, suffix, Some("mp4"))?;
+  let mut cmd = Command::new("ffmpeg");
+  cmd.arg("-y").arg("-ss").arg(format!("{:.3}", start))
+    .arg("-i").arg(filename);
+  if let Some(length) = length {
+    cmd.arg("-t").arg(format!("{:.3}", length));
+  }
+  cmd.arg("-map").arg("0:v:0")
+    .arg("-map").arg("0:a?")
+    .arg("-c:v").arg("libx264").arg("-preset").arg("veryfast").arg("-crf").arg("18")
+    .arg("-c:a").arg("aac").arg("-b:a").arg("192k")
+    .arg(&outfile);
+  run(&mut cmd, "cut re-encode").await?;
+
+  Ok(outfile)
+}
+
+/// Concatenate [parts] of same format (cut from [filename]) into one file
+pub async fn join(parts: &[String], filename: &String) -> Result<String> {
+  let outfile = out_file(filename, "joined", None)?;
+  let list = out_file(filename, "joined", Some("txt"))?;
+  let list_data : String = parts.iter()
+    .map(|x| std::fs::canonicalize(x)
+         .map(|x| format!("file '{}'\n", x.to_string_lossy().replace('\'', "'\\''"))))
+    .collect::<std::io::Result<_>>()?;
+  std::fs::write(&list, list_data)?;
+  let mut cmd = Command::new("ffmpeg");
+  cmd.arg("-y")
+    .arg("-f").arg("concat")
+    .arg("-safe").arg("0")
+    .arg("-i").arg(&list)
+    .arg("-c").arg("copy")
+    .arg(&outfile);
+  run(&mut cmd, "join").await?;
+
+  Ok(outfile)
+}
+
+/// Stream properties of media file
+#[derive(Debug, Clone, Default)]
+pub struct Probe {
+  pub duration: Option<f64>,
+  pub width: Option<i64>,
+  pub height: Option<i64>,
+  /// None if there is no video stream
+  pub vcodec: Option<String>,
+}
+
+/// Read duration, codecs and video dimensions of file with ffprobe
+pub async fn probe(filename: &String) -> Result<Probe> {
+  let mut cmd = Command::new("ffprobe");
+  cmd.arg("-v").arg("error")
+    .arg("-show_entries").arg("format=duration:stream=codec_type,codec_name,width,height:stream_disposition=attached_pic")
+    .arg("-of").arg("json")
+    .arg(filename);
+  log::info!("ffmpeg::probe {:?}", &cmd);
+  let output = utils::output(&mut cmd).await?;
+  if !output.status.success() {
+    log::error!("stderr: {:?}", String::from_utf8_lossy(&output.stderr));
+    return Err(Error::msg("Command ffprobe failed"));
+  }
+  let res : serde_json::Value = serde_json::from_slice(&output.stdout)?;
+  let streams = res["streams"].as_array().cloned().unwrap_or_default();
+  // cover art is a "video" stream too
+  let stream = |typ: &str| streams.iter()
+    .find(|x| x["codec_type"] == typ && x["disposition"]["attached_pic"] != 1)
+    .cloned()
+    .unwrap_or_default();
+  let video = stream("video");
+  Ok(Probe {
+    duration: res["format"]["duration"].as_str().and_then(|x| x.parse().ok()),
+    width: video["width"].as_i64(),
+    height: video["height"].as_i64(),
+    vcodec: video["codec_name"].as_str().map(|x| x.to_string()),
+  })
+}
+
+/// Telegram ignores thumbnails bigger than this on any side
+const THUMB_SIZE: i64 = 320;
+
+/// Protocols ffmpeg may open for [input]: it comes from extractor metadata when it is a URL
+fn input_protocols(input: &str) -> &'static str {
+  if input.starts_with("http://") || input.starts_with("https://") {
+    "http,https,tls,tcp"
+  } else {
+    "file"
+  }
+}
+
+/// Make jpeg thumbnail next to [filename] of [input]: local video (frame at [seek] seconds)
+/// or image URL.
+pub async fn thumbnail(input: &str, seek: Option<f64>, filename: &String) -> Result<String> {
+  let outfile = out_file(filename, "thumb", Some("jpg"))?;
+  let mut cmd = Command::new("ffmpeg");
+  cmd.arg("-y");
+  if let Some(seek) = seek {
+    cmd.arg("-ss").arg(format!("{:.3}", seek));
+  }
+  cmd.arg("-protocol_whitelist").arg(input_protocols(input))
+    .arg("-i").arg(input)
+    .arg("-frames:v").arg("1")
+    .arg("-vf").arg(format!("scale={0}:{0}:force_original_aspect_ratio=decrease", THUMB_SIZE))
+    .arg("-q:v").arg("4")
+    .arg(&outfile);
+  run(&mut cmd, "thumbnail").await?;
+  Ok(outfile)
+}
+
+/// Leave room for container overhead and bitrate fluctuations
+const FIT_SIZE_RATIO: f64 = 0.93;
+const FIT_AUDIO_BITRATE: i64 = 96_000;
+/// Below this video is not worth watching
+const FIT_MIN_VIDEO_BITRATE: i64 = 100_000;
 
+/// Aim parts at this share of the limit, segments are cut at keyframes
+const SPLIT_SIZE_RATIO: f64 = 0.9;
+/// Shrink segment time this much when some part is still too big
+const SPLIT_RETRY_RATIO: f64 = 0.7;
+const SPLIT_ATTEMPTS: usize = 3;
+
+/// Run ffmpeg, failing with [name] in error on non-zero exit
+async fn run(cmd: &mut Command, name: &str) -> Result<()> {
+  log::info!("ffmpeg::{} {:?}", name, &cmd);
+  let output = utils::output(cmd).await?;
   if !output.status.success() {
-    // Err(output.stderr.to_string())
     log::error!("stdout: {:?}\nstderr: {:?}",
-                std::str::from_utf8(&output.stdout).unwrap(),
-                std::str::from_utf8(&output.stderr).unwrap());
-    Err(Error::msg("Command ffmpeg::cut failed"))
-  } else { Ok(()) }?;
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr));
+    return Err(anyhow!("Command ffmpeg::{} failed", name));
+  }
+  Ok(())
+}
+
+/// Two-pass h264 re-encode with bitrate computed from [duration] (seconds)
+/// so result fits into [max_filesize].
+pub async fn fit(filename: &String, duration: f64, max_filesize: i64) -> Result<String> {
+  let outfile = out_file(filename, "fit", Some("mp4"))?;
+  let passlog = format!("{}.passlog", outfile);
+  let total_bitrate = (max_filesize as f64 * 8.0 * FIT_SIZE_RATIO / duration) as i64;
+  let video_bitrate = total_bitrate - FIT_AUDIO_BITRATE;
+  if video_bitrate < FIT_MIN_VIDEO_BITRATE {
+    return Err(anyhow!("Sorry, video is too long to fit into {}",
+                       utils::human_size(max_filesize as f64)));
+  }
+  let video_args = [
+    "-c:v", "libx264", "-preset", "medium",
+    "-b:v", &video_bitrate.to_string(),
+    "-maxrate", &(video_bitrate * 3 / 2).to_string(),
+    "-bufsize", &(video_bitrate * 2).to_string(),
+    "-passlogfile", &passlog,
+  ].map(|x| x.to_string());
+  let mut pass1 = Command::new("ffmpeg");
+  pass1.arg("-y").arg("-i").arg(filename)
+    .args(&video_args)
+    .arg("-pass").arg("1")
+    .arg("-an")
+    .arg("-f").arg("null").arg("/dev/null");
+  run(&mut pass1, "fit pass 1").await?;
+  let mut pass2 = Command::new("ffmpeg");
+  pass2.arg("-y").arg("-i").arg(filename)
+    .args(&video_args)
+    .arg("-pass").arg("2")
+    .arg("-c:a").arg("aac")
+    .arg("-b:a").arg(FIT_AUDIO_BITRATE.to_string())
+    .arg("-movflags").arg("+faststart")
+    .arg(&outfile);
+  run(&mut pass2, "fit pass 2").await?;
+
+  let size = std::fs::metadata(&outfile)
+    .context("Re-encoded file is missing")?
+    .len() as i64;
+  if size > max_filesize {
+    return Err(anyhow!("Sorry, re-encoded file is still too big: {}",
+                       utils::human_size(size as f64)));
+  }
+  Ok(outfile)
+}
+
+/// Split file into parts at keyframes (without re-encoding), each smaller than [max_filesize].
+/// Return parts in order, or file itself if it is small enough.
+pub async fn split(filename: &String, duration: f64, max_filesize: i64) -> Result<Vec<String>> {
+  let size = std::fs::metadata(filename)?.len() as i64;
+  if size <= max_filesize {
+    return Ok(vec![filename.clone()]);
+  }
+  let mut segment_time = duration * max_filesize as f64 * SPLIT_SIZE_RATIO / size as f64;
+  for attempt in 0..SPLIT_ATTEMPTS {
+    let pattern = out_file(filename, &format!("part{}_%03d", attempt), None)?;
+    let mut cmd = Command::new("ffmpeg");
+    // segment muxer fails on subtitle and data streams
+    cmd.arg("-y").arg("-i").arg(filename)
+      .arg("-map").arg("0:v?")
+      .arg("-map").arg("0:a?")
+      .arg("-c").arg("copy")
+      .arg("-f").arg("segment")
+      .arg("-segment_time").arg(format!("{:.3}", segment_time))
+      .arg("-reset_timestamps").arg("1")
+      .arg(&pattern);
+    run(&mut cmd, "split").await?;
+
+    let prefix = path::Path::new(&pattern);
+    let dir = prefix.parent()
+      .and_then(|x| x.to_str())
+      .filter(|x| !x.is_empty())
+      .map_or(".".to_string(), |x| x.to_string());
+    let name = prefix.file_name()
+      .and_then(|x| x.to_str())
+      .and_then(|x| x.split("%03d").next())
+      .ok_or(anyhow!("bad split pattern {}", pattern))?
+      .to_string();
+    let mut parts = utils::find_files_pat(&dir, &name)?;
+    parts.sort();
+    let too_big = parts.iter()
+      .any(|x| std::fs::metadata(x).map_or(true, |m| m.len() as i64 > max_filesize));
+    if !too_big && !parts.is_empty() {
+      log::info!("ffmpeg::split {} into {} parts", filename, parts.len());
+      return Ok(parts);
+    }
+    for part in parts {
+      std::fs::remove_file(part)?;
+    }
+    segment_time *= SPLIT_RETRY_RATIO;
+  }
+  Err(anyhow!("Sorry, could not split file into parts smaller than {}",
+              utils::human_size(max_filesize as f64)))
+}
+
+
+/// Flac bitrate is unknown before encoding, this is typical for music
+const FLAC_KBPS: u32 = 900;
+
+/// Encoder and extension of [format], None if audio is kept as is
+fn audio_encoder(format: &AudioFormat) -> Option<(&'static str, &'static str)> {
+  match format {
+    AudioFormat::Original => None,
+    AudioFormat::Mp3 => Some(("libmp3lame", "mp3")),
+    AudioFormat::M4a => Some(("aac", "m4a")),
+    AudioFormat::Opus => Some(("libopus", "opus")),
+    AudioFormat::Flac => Some(("flac", "flac")),
+  }
+}
+
+/// Expected bitrate of audio converted to [format], None if not converted
+pub fn audio_kbps(format: &AudioFormat, abitrate: u32) -> Option<u32> {
+  match format {
+    AudioFormat::Original => None,
+    AudioFormat::Flac => Some(FLAC_KBPS),
+    _ => Some(abitrate),
+  }
+}
+
+/// Convert first audio stream of [filename] to [format], [abitrate] in kbps
+pub async fn convert_audio(filename: &String, format: &AudioFormat, abitrate: u32) -> Result<String> {
+  let (encoder, ext) = match audio_encoder(format) {
+    Some(x) => x,
+    None => return Ok(filename.clone()),
+  };
+  let outfile = out_file(filename, "audio", Some(ext))?;
+  let mut cmd = Command::new("ffmpeg");
+  cmd.arg("-y").arg("-i").arg(filename)
+    .arg("-map").arg("0:a:0")
+    .arg("-vn")
+    .arg("-c:a").arg(encoder);
+  if *format != AudioFormat::Flac {
+    cmd.arg("-b:a").arg(format!("{}k", abitrate));
+  }
+  cmd.arg(&outfile);
+  run(&mut cmd, "convert audio").await?;
+
+  Ok(outfile)
+}
+
+
+/// Tags written into audio file
+#[derive(Debug, Clone, Default)]
+pub struct Tags {
+  pub title: Option<String>,
+  pub artist: Option<String>,
+  pub album: Option<String>,
+  pub date: Option<String>,
+  /// Like 1/3 for parts of split file
+  pub track: Option<String>,
+  /// Source URL, goes to comment
+  pub url: Option<String>,
+}
+
+/// Side of square cover art
+const COVER_SIZE: i64 = 600;
+
+/// Make square jpeg cover next to [filename] from center of image [input] (local or URL)
+pub async fn cover(input: &str, filename: &String) -> Result<String> {
+  let outfile = out_file(filename, "cover", Some("jpg"))?;
+  let mut cmd = Command::new("ffmpeg");
+  cmd.arg("-y")
+    .arg("-protocol_whitelist").arg(input_protocols(input))
+    .arg("-i").arg(input)
+    .arg("-frames:v").arg("1")
+    .arg("-vf").arg(format!("crop='min(iw,ih)':'min(iw,ih)',scale={0}:{0}", COVER_SIZE))
+    .arg("-q:v").arg("2")
+    .arg(&outfile);
+  run(&mut cmd, "cover").await?;
+  Ok(outfile)
+}
+
+/// Write [tags] and [cover] image into audio [filename], streams are copied.
+/// Cover is skipped for containers ffmpeg can not embed it into (ogg, webm).
+pub async fn tag(filename: &String, tags: &Tags, cover: Option<&str>) -> Result<String> {
+  let outfile = out_file(filename, "tagged", None)?;
+  let ext = path::Path::new(&outfile).extension()
+    .and_then(|x| x.to_str())
+    .unwrap_or_default()
+    .to_string();
+  let cover = cover.filter(|_| matches!(&*ext, "mp3" | "m4a" | "mp4" | "flac"));
+  let mut cmd = Command::new("ffmpeg");
+  cmd.arg("-y").arg("-i").arg(filename);
+  if let Some(cover) = cover {
+    cmd.arg("-i").arg(cover);
+  }
+  cmd.arg("-map").arg("0:a");
+  if cover.is_some() {
+    cmd.arg("-map").arg("1:v")
+      .arg("-disposition:v:0").arg("attached_pic")
+      .arg("-metadata:s:v").arg("title=Cover")
+      .arg("-metadata:s:v").arg("comment=Cover (front)");
+  }
+  cmd.arg("-c").arg("copy");
+  if ext == "mp3" {
+    // v2.4 frames are not understood by some players
+    cmd.arg("-id3v2_version").arg("3");
+  }
+  let Tags {title, artist, album, date, track, url} = tags.clone();
+  let values = [("title", title), ("artist", artist), ("album", album),
+                ("date", date), ("track", track), ("comment", url)];
+  for (key, value) in values {
+    if let Some(value) = value {
+      cmd.arg("-metadata").arg(format!("{}={}", key, value));
+    }
+  }
+  cmd.arg(&outfile);
+  run(&mut cmd, "tag").await?;
 
-  // let result : Video = serde_json::from_slice(&output.stdout)?;
-  
   Ok(outfile)
 }
@@ -0,0 +1,160 @@
+use std::num::NonZeroUsize;
+use lru::LruCache;
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use crate::user_state::{UserConfig, Mode, Cut, CutMode, AudioFormat};
+use crate::utils;
+
+/// Identifies delivered media independently of URL it was requested by
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct MediaKey {
+  /// "<extractor>:<video id>"
+  pub video_id: String,
+  pub format_id: String,
+  pub cut: Option<Cut>,
+  /// Set when media is cut
+  #[serde(default)]
+  pub cut_mode: Option<CutMode>,
+  pub mode: Mode,
+  /// Format and bitrate audio was converted to
+  #[serde(default)]
+  pub audio: Option<(AudioFormat, u32)>,
+  /// Sent as document by user's choice
+  #[serde(default)]
+  pub as_file: bool,
+}
+
+/// Request as it was received: same URL with same settings gives same media
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct RequestKey {
+  pub url: String,
+  /// Format picked by user, None if chosen automatically
+  pub format_id: Option<String>,
+  pub userconf: UserConfig,
+}
+
+/// Media [key] request resolved to at [at] (unix seconds)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResolvedRequest {
+  pub key: MediaKey,
+  pub at: u64,
+}
+
+/// Uploaded file, one per part
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CachedFile {
+  pub file_id: String,
+  pub caption: String,
+  /// Stored by telegram as document, resend with sendDocument
+  #[serde(default)]
+  pub document: bool,
+}
+
+/// Cache file layout, old caches may be dropped instead of upgraded.
+const SCHEMA_VERSION: u64 = 1;
+
+#[derive(Deserialize, Serialize, Default)]
+struct JsonFile {
+  version: u64,
+  media: Vec<(MediaKey, Vec<CachedFile>)>,
+  requests: Vec<(RequestKey, ResolvedRequest)>,
+}
+
+fn migrate(version: u64, data: serde_json::Value) -> Result<serde_json::Value> {
+  match version {
+    SCHEMA_VERSION => Ok(data),
+    _ => Err(anyhow!("Unsupported file cache schema version {}", version)),
+  }
+}
+
+/// Most media kept, least recently used are dropped
+const MAX_MEDIA: usize = 10000;
+/// Most requests kept, least recently used are dropped
+const MAX_REQUESTS: usize = 10000;
+/// Request is resolved again after this many seconds: content of URL may change
+const REQUEST_TTL: u64 = 3600;
+
+fn now() -> u64 {
+  std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map(|x| x.as_secs())
+    .unwrap_or(0)
+}
+
+/// Telegram file_ids of already delivered media, see [FileCache::snapshot] to save to [path].
+pub struct FileCache {
+  path: Option<String>,
+  media: LruCache<MediaKey, Vec<CachedFile>>,
+  requests: LruCache<RequestKey, ResolvedRequest>,
+}
+
+impl FileCache {
+  /// Cache kept in memory only
+  pub fn new() -> FileCache {
+    FileCache {
+      path: None,
+      media: LruCache::new(NonZeroUsize::new(MAX_MEDIA).unwrap()),
+      requests: LruCache::new(NonZeroUsize::new(MAX_REQUESTS).unwrap()),
+    }
+  }
+
+  pub fn open(path: String) -> Result<FileCache> {
+    let file = utils::load_json::<JsonFile>(&path, migrate)?.unwrap_or_default();
+    log::info!("Loaded {} cached files from {}", file.media.len(), path);
+    let mut cache = FileCache {path: Some(path), .. FileCache::new()};
+    // saved most recent first
+    for (key, files) in file.media.into_iter().rev() {
+      cache.media.put(key, files);
+    }
+    for (request, resolved) in file.requests.into_iter().rev() {
+      cache.requests.put(request, resolved);
+    }
+    Ok(cache)
+  }
+
+  /// Path and content to write, None if cache is not persisted
+  pub fn snapshot(&self) -> Result<Option<(String, Vec<u8>)>> {
+    let path = match &self.path {
+      Some(path) => path,
+      None => return Ok(None),
+    };
+    let file = JsonFile {
+      version: SCHEMA_VERSION,
+      media: self.media.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+      requests: self.requests.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+    };
+    Ok(Some((path.clone(), serde_json::to_vec(&file)?)))
+  }
+
+  pub fn get(&mut self, key: &MediaKey) -> Option<Vec<CachedFile>> {
+    self.media.get(key).cloned()
+  }
+
+  /// Media [request] resolved to less than [REQUEST_TTL] ago
+  pub fn get_by_request(&mut self, request: &RequestKey) -> Option<(MediaKey, Vec<CachedFile>)> {
+    let key = self.requests.get(request)
+      .filter(|x| now().saturating_sub(x.at) < REQUEST_TTL)?
+      .key.clone();
+    self.get(&key).map(|files| (key, files))
+  }
+
+  /// Forget media whose file_ids do not work anymore
+  pub fn remove(&mut self, key: &MediaKey) {
+    self.media.pop(key);
+    let requests : Vec<_> = self.requests.iter()
+      .filter(|(_, x)| x.key == *key)
+      .map(|(request, _)| request.clone())
+      .collect();
+    for request in requests {
+      self.requests.pop(&request);
+    }
+  }
+
+  /// Remember [files] delivered for [key] (and [request] resolving to it)
+  pub fn put(&mut self, request: RequestKey, key: MediaKey, files: Option<Vec<CachedFile>>) {
+    if let Some(files) = files {
+      self.media.put(key.clone(), files);
+    }
+    self.requests.put(request, ResolvedRequest {key, at: now()});
+  }
+}
@@ -2,7 +2,8 @@ use anyhow::{Result, anyhow};
 use itertools::Itertools;
 use crate::config::Config;
 use crate::ytdlp;
-use crate::user_state::{UserConfig, Quality, Mode};
+use crate::ffmpeg;
+use crate::user_state::{UserConfig, Quality, Mode, Oversize, Cut};
 
 
 pub struct ChosenFormat {
@@ -10,6 +11,8 @@ pub struct ChosenFormat {
   pub ext: String,
   pub vcodec: Option<String>,
   pub acodec: Option<String>,
+  /// Too big to send as is, how to make it fit max_filesize
+  pub oversize: Option<Oversize>,
 }
 
 impl From<ytdlp::Format> for ChosenFormat {
@@ -19,7 +22,20 @@ impl From<ytdlp::Format> for ChosenFormat {
       format_id: Some(format_id.clone()),
       ext: ext.clone(),
       vcodec: vcodec.clone(),
-      acodec: acodec.clone()}
+      acodec: acodec.clone(),
+      oversize: None}
+  }
+}
+
+/// Size of sent audio, it does not depend on [format] when converted in audio mode
+fn audio_size(userconf: &UserConfig, video: &ytdlp::Video, format: &ytdlp::Format) -> Option<i64> {
+  let kbps = match userconf.mode {
+    Mode::Audio => ffmpeg::audio_kbps(&userconf.audio_format, userconf.abitrate),
+    Mode::Video => None,
+  };
+  match (kbps, video.duration) {
+    (Some(kbps), Some(duration)) => Some((duration * kbps as f64 * 1000.0 / 8.0) as i64),
+    _ => format.get_filesize(),
   }
 }
 
@@ -31,8 +47,8 @@ fn choose_format_audio(conf: &Config, userconf: &UserConfig, video: &ytdlp::Vide
   let mut formats : Vec<ytdlp::Format> = video.formats.clone()
     .into_iter()
     .sorted_by_key(|x| x.get_filesize().unwrap_or(max_filesize))
-    .filter(|x| x.get_filesize()
-            .map_or(false, |filesize| filesize < max_filesize))
+    .filter(|x| audio_size(userconf, video, x)
+            .is_some_and(|filesize| filesize < max_filesize))
     .filter(|format| {
       let (video, audio) = format.get_video_audio();
       // log::debug!("DBG: {:?} {}", video.clone(), audio.clone());
@@ -116,8 +132,65 @@ fn choose_format_video(conf: &Config, userconf: &UserConfig, video: &ytdlp::Vide
 }
 
 
-pub fn choose_format(conf: &Config, userconf: &UserConfig, video: &ytdlp::Video) -> Result<ChosenFormat> {
+/// Do not send more parts than this when splitting
+const MAX_SPLIT_PARTS: i64 = 10;
+
+/// Smallest video (merged with smallest audio) to re-encode, preferring at least 360p
+fn choose_format_reencode(userconf: &UserConfig, video: &ytdlp::Video) -> Result<ytdlp::Format> {
+  let UserConfig {vcodec_exclude, ..} = userconf.clone();
+  let audio_format = video.formats.iter()
+    .filter(|format| {
+      let (video, audio) = format.get_video_audio();
+      video == "none" && audio != "none"
+    })
+    .filter(|x| x.get_filesize().is_some())
+    .min_by_key(|x| x.get_filesize());
+  let formats : Vec<_> = video.formats.iter()
+    .filter_map(|format| {
+      let (video, audio) = format.get_video_audio();
+      let excluded = vcodec_exclude.iter()
+        .any(|c| video.starts_with(c));
+      match (&*video, &*audio, excluded) {
+        ("none", _, _) => None,
+        (_, _, true) => None,
+        (_, "none", false) => audio_format.map(
+          |audio_format| format.add_audio(audio_format)),
+        (_, _, false) => Some(format.clone()),
+      }
+    })
+    .filter(|x| x.get_filesize().is_some())
+    .sorted_by_key(|x| x.get_filesize())
+    .collect();
+  log::debug!("DBG: Formats to re-encode: {}", ytdlp::FormatVec(formats.clone()));
+  formats.iter()
+    .find(|x| x.height.unwrap_or(0) >= 360)
+    .or(formats.first())
+    .cloned()
+    .ok_or(anyhow!("Sorry, file is too big and there is no format to re-encode"))
+}
+
+
+/// Part of whole video size the biggest file of [cut] takes
+fn cut_share(cut: &Cut, video: &ytdlp::Video) -> Option<f64> {
+  let duration = video.duration.filter(|x| *x > 0.0)?;
+  let length = if cut.join {
+    cut.duration(Some(duration))?
+  } else {
+    cut.segments.iter()
+      .filter_map(|x| x.duration(Some(duration)))
+      .fold(0.0, f64::max)
+  };
+  Some((length / duration).clamp(0.01, 1.0))
+}
+
+/// Sizes are estimated for [cut] part of video only when given
+pub fn choose_format(conf: &Config, userconf: &UserConfig, video: &ytdlp::Video, cut: Option<&Cut>) -> Result<ChosenFormat> {
   log::debug!("DBG: All formats: {}", ytdlp::FormatVec(video.formats.clone()));
+  // format of whole video fits when its cut part does
+  let conf = &match cut.and_then(|cut| cut_share(cut, video)) {
+    Some(share) => Config {max_filesize: (conf.max_filesize as f64 / share) as i64, .. conf.clone()},
+    None => conf.clone(),
+  };
   let res = 
     match userconf {
       UserConfig {mode: Mode::Video, ..} =>
@@ -125,6 +198,65 @@ pub fn choose_format(conf: &Config, userconf: &UserConfig, video: &ytdlp::Video)
       UserConfig {mode: Mode::Audio, ..} => {
         choose_format_audio(conf, userconf, video)
       }
-    }?;
-  Ok(res.into())
+    };
+  match (res, userconf) {
+    (Ok(res), _) => Ok(res.into()),
+    (Err(_), UserConfig {mode: Mode::Video, oversize: Oversize::Reencode, ..})
+      if video.duration.is_some() => {
+        let format = choose_format_reencode(userconf, video)?;
+        Ok(ChosenFormat {oversize: Some(Oversize::Reencode), .. format.into()})
+      },
+    (Err(_), UserConfig {mode, oversize: Oversize::Split, ..})
+      if video.duration.is_some() => {
+        let conf = Config {max_filesize: conf.max_filesize * MAX_SPLIT_PARTS, .. conf.clone()};
+        let format = match mode {
+          Mode::Video => choose_format_video(&conf, userconf, video),
+          Mode::Audio => choose_format_audio(&conf, userconf, video),
+        }?;
+        Ok(ChosenFormat {oversize: Some(Oversize::Split), .. format.into()})
+      },
+    (Err(e), _) => Err(e),
+  }
+}
+
+
+/// Max number of formats offered to pick from
+const MAX_CANDIDATES: usize = 20;
+
+/// Formats user can pick from for current mode, best first.
+/// Video only formats are merged with chosen audio.
+pub fn candidates(conf: &Config, userconf: &UserConfig, video: &ytdlp::Video) -> Vec<ytdlp::Format> {
+  let Config {max_filesize, ..} = conf.clone();
+  let audio_format = choose_format_audio(conf, userconf, video).ok();
+  video.formats.iter()
+    .filter_map(|format| {
+      let (video, audio) = format.get_video_audio();
+      match (&userconf.mode, &*video, &*audio) {
+        (_, "none", "none") => None,
+        (Mode::Audio, "none", _) => Some(format.clone()),
+        (Mode::Audio, _, _) => None,
+        (Mode::Video, "none", _) => None,
+        (Mode::Video, _, "none") => audio_format.as_ref().map(
+          |audio_format| format.add_audio(audio_format)),
+        (Mode::Video, _, _) => Some(format.clone()),
+      }
+    })
+    // unknown size is allowed, user sees "?" in label
+    .filter(|x| x.get_filesize()
+            .is_none_or(|filesize| filesize < max_filesize))
+    .sorted_by_key(|x| (-x.height.unwrap_or(0), -x.tbr.unwrap_or(0.0) as i64))
+    .take(MAX_CANDIDATES)
+    .collect()
+}
+
+/// Find format by id, "video+audio" ids are merged with [ytdlp::Format::add_audio]
+pub fn find_format(video: &ytdlp::Video, format_id: &str) -> Result<ChosenFormat> {
+  let find = |id: &str| video.formats.iter()
+    .find(|x| x.format_id == id)
+    .ok_or(anyhow!("Format {} is not available anymore", id));
+  let format = match format_id.split_once('+') {
+    Some((video_id, audio_id)) => find(video_id)?.add_audio(find(audio_id)?),
+    None => find(format_id)?.clone(),
+  };
+  Ok(format.into())
 }
@@ -0,0 +1,24 @@
+use crate::telegram_messages::{InlineKeyboardButton, InlineKeyboardMarkup};
+use crate::ytdlp;
+
+/// Prefix of callback data of format picker buttons
+pub const PREFIX: &str = "pick:";
+/// Callback data (without prefix) of dismiss button
+pub const CANCEL: &str = "cancel";
+/// Telegram limit for callback_data
+const MAX_CALLBACK_DATA: usize = 64;
+
+
+fn button(text: String, data: &str) -> InlineKeyboardButton {
+  InlineKeyboardButton {text, callback_data: format!("{}{}", PREFIX, data)}
+}
+
+/// One button per format, pressing it downloads exactly that format_id
+pub fn keyboard(formats: &[ytdlp::Format]) -> InlineKeyboardMarkup {
+  let inline_keyboard = formats.iter()
+    .filter(|format| PREFIX.len() + format.format_id.len() <= MAX_CALLBACK_DATA)
+    .map(|format| vec![button(format.label(), &format.format_id)])
+    .chain(std::iter::once(vec![button("Cancel".to_string(), CANCEL)]))
+    .collect();
+  InlineKeyboardMarkup {inline_keyboard}
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::{Result, anyhow};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use crate::config::Config;
+use crate::user_state::State;
+use crate::commands;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobKind {
+  /// Download format chosen by user, automatically chosen if None
+  Download {format_id: Option<String>},
+  /// Describe URL and offer formats to pick from
+  PickFormat,
+  /// Describe playlist (only [items] if set) and offer to download its entries
+  Playlist {items: Option<String>},
+}
+
+/// Request waiting for (or occupying) a worker
+#[derive(Debug, Clone)]
+pub struct Job {
+  pub chat_id: i64,
+  /// Who asked for it: jobs are limited and cancelled per user of chat
+  pub user_id: i64,
+  /// Key of user config to use: chat or group member
+  pub settings_id: i64,
+  pub url: url::Url,
+  /// Status message to report progress in
+  pub message_id: i64,
+  /// Request message to send media as reply to
+  pub reply_to: Option<i64>,
+  pub kind: JobKind,
+}
+
+impl Job {
+  /// Names of files of this job in download dir start with it,
+  /// status message is unique per chat
+  pub fn file_prefix(&self) -> String {
+    format!("{}_{}_", self.chat_id, self.message_id)
+  }
+}
+
+impl std::fmt::Display for Job {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Job({}, {}, {:?})", self.chat_id, self.url, self.kind)
+  }
+}
+
+/// Queued + running jobs per (chat, user): (job id, cancel token)
+type Jobs = HashMap<(i64, i64), Vec<(u64, CancellationToken)>>;
+
+/// Runs download jobs in background, at most [Config::workers] at once.
+pub struct JobQueue {
+  conf: Arc<Config>,
+  state: Arc<State>,
+  workers: Arc<Semaphore>,
+  next_id: AtomicU64,
+  jobs: Mutex<Jobs>,
+}
+
+impl JobQueue {
+  pub fn new(conf: Arc<Config>, state: Arc<State>) -> Arc<JobQueue> {
+    let workers = Arc::new(Semaphore::new(conf.workers));
+    Arc::new(JobQueue {conf, state, workers, next_id: AtomicU64::new(0),
+                       jobs: Mutex::new(HashMap::new())})
+  }
+
+  /// Check if [n] more jobs of user in chat fit into limits.
+  /// Batch (playlist) may exceed per user limit up to [Config::max_playlist].
+  pub fn check(&self, chat_id: i64, user_id: i64, n: usize) -> Result<()> {
+    let jobs = self.jobs.lock().unwrap();
+    self.check_locked(&jobs, (chat_id, user_id), n)
+  }
+
+  fn check_locked(&self, jobs: &Jobs, owner: (i64, i64), n: usize) -> Result<()> {
+    let total : usize = jobs.values().map(|x| x.len()).sum();
+    let user = jobs.get(&owner).map_or(0, |x| x.len());
+    let user_limit = if n > 1 {
+      std::cmp::max(self.conf.max_user_jobs, self.conf.max_playlist)
+    } else {
+      self.conf.max_user_jobs
+    };
+    if user + n > user_limit {
+      return Err(anyhow!("You already have {} downloads in progress, wait for them to finish", user));
+    }
+    if total + n > self.conf.max_jobs {
+      return Err(anyhow!("Bot is busy, try again later"));
+    }
+    Ok(())
+  }
+
+  /// Check limits and register [n] jobs for (chat, user) [owner]
+  fn reserve(&self, owner: (i64, i64), n: usize) -> Result<Vec<(u64, CancellationToken)>> {
+    let mut jobs = self.jobs.lock().unwrap();
+    self.check_locked(&jobs, owner, n)?;
+    let reserved : Vec<_> = (0..n)
+      .map(|_| (self.next_id.fetch_add(1, Ordering::Relaxed), CancellationToken::new()))
+      .collect();
+    jobs.entry(owner).or_default().extend(reserved.iter().cloned());
+    Ok(reserved)
+  }
+
+  fn release(&self, owner: (i64, i64), id: u64) {
+    let mut jobs = self.jobs.lock().unwrap();
+    if let Some(user_jobs) = jobs.get_mut(&owner) {
+      user_jobs.retain(|(id_, _)| *id_ != id);
+      if user_jobs.is_empty() {
+        jobs.remove(&owner);
+      }
+    }
+  }
+
+  /// Number of jobs queued or running for user in chat
+  pub fn user_jobs(&self, chat_id: i64, user_id: i64) -> usize {
+    self.jobs.lock().unwrap().get(&(chat_id, user_id)).map_or(0, |x| x.len())
+  }
+
+  /// Cancel all queued and running jobs of user in chat, return their number.
+  pub fn cancel(&self, chat_id: i64, user_id: i64) -> usize {
+    let jobs = self.jobs.lock().unwrap();
+    let user_jobs = jobs.get(&(chat_id, user_id)).map_or(&[][..], |x| &x[..]);
+    for (_, token) in user_jobs {
+      token.cancel();
+    }
+    user_jobs.len()
+  }
+
+  /// Put job in queue, fails if user or global limit is exceeded.
+  pub fn submit(self: &Arc<Self>, job: Job) -> Result<()> {
+    self.submit_batch(vec![job])
+  }
+
+  /// Put jobs of one user in chat in queue, all or none.
+  pub fn submit_batch(self: &Arc<Self>, jobs: Vec<Job>) -> Result<()> {
+    let owner = match jobs.first() {
+      Some(job) => (job.chat_id, job.user_id),
+      None => return Ok(()),
+    };
+    let reserved = self.reserve(owner, jobs.len())?;
+    for (job, (id, token)) in jobs.into_iter().zip(reserved) {
+      self.spawn(job, id, token);
+    }
+    Ok(())
+  }
+
+  fn spawn(self: &Arc<Self>, job: Job, id: u64, token: CancellationToken) {
+    log::info!("Queued {}", job);
+    let queue = self.clone();
+    tokio::spawn(async move {
+      let run = async {
+        let _permit = queue.workers.clone().acquire_owned().await?;
+        commands::run_job(&queue.conf, &queue.state, &job).await
+      };
+      // dropping [run] kills yt-dlp/ffmpeg processes started by it
+      let res = tokio::select! {
+        _ = token.cancelled() => None,
+        res = run => Some(res),
+      };
+      let res = match res {
+        Some(res) => res,
+        None => {
+          log::info!("Cancelled {}", job);
+          commands::job_cancelled(&queue.conf, &job).await
+        },
+      };
+      if let Err(e) = res {
+        log::error!("Error: {:?}", e);
+      }
+      queue.release((job.chat_id, job.user_id), id);
+    });
+  }
+}
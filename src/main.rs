@@ -9,54 +9,176 @@ mod user_state;
 mod ffmpeg;
 mod format_chooser;
 mod commands;
+mod webhook;
+mod jobs;
+mod progress;
+mod storage;
+mod settings_menu;
+mod format_picker;
+mod file_cache;
+mod playlist_prompt;
+mod rate_limit;
+mod mime;
+
+use std::sync::Arc;
+use config::Config;
+use user_state::State;
+use jobs::JobQueue;
+use rate_limit::RateLimiter;
+
+/// Env var parsed as [T], unparsable value is a mistake
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+  match std::env::var(name) {
+    Ok(x) if !x.trim().is_empty() => x.trim().parse::<T>()
+      .unwrap_or_else(|_| panic!("Could not parse {}={}", name, x)),
+    _ => default,
+  }
+}
+
+/// Like [env_or], for limits where 0 would stop everything
+fn env_nonzero(name: &str, default: usize) -> usize {
+  match env_or(name, default) {
+    0 => panic!("{} should be more than 0", name),
+    x => x,
+  }
+}
+
+/// Boolean env var: 1/true/yes/on or 0/false/no/off, anything else is a mistake
+fn env_flag(name: &str, default: bool) -> bool {
+  match std::env::var(name).map(|x| x.trim().to_lowercase()).as_deref() {
+    Err(_) | Ok("") => default,
+    Ok("1" | "true" | "yes" | "on") => true,
+    Ok("0" | "false" | "no" | "off") => false,
+    Ok(x) => panic!("{} should be true or false, not {}", name, x),
+  }
+}
+
+fn webhook_config() -> Option<config::WebhookConfig> {
+  let url = std::env::var("WEBHOOK_URL").ok()?;
+  let secret_token = std::env::var("WEBHOOK_SECRET")
+    .expect("Specify WEBHOOK_SECRET env var when WEBHOOK_URL is set.");
+  let listen = std::env::var("WEBHOOK_LISTEN")
+    .unwrap_or_else(|_| "0.0.0.0:8443".to_string())
+    .parse()
+    .expect("WEBHOOK_LISTEN should be ip:port");
+  Some(config::WebhookConfig {url, listen, secret_token})
+}
+
+/// Receive updates from embedded http server
+async fn run_webhook(conf: &Arc<Config>, state: &Arc<State>, queue: &Arc<JobQueue>, limiter: &Arc<RateLimiter>, webhook: &config::WebhookConfig) -> Result<()> {
+  let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+  telegram::set_webhook(
+    &conf.bot_url(), webhook.url.clone(), webhook.secret_token.clone(),
+    conf.allowed_updates.clone()).await?;
+  let server = webhook::serve(webhook, tx);
+  let dispatcher = async {
+    while let Some(msg) = rx.recv().await {
+      // take everything arrived so far as one batch
+      let mut messages = vec![msg];
+      while let Ok(msg) = rx.try_recv() {
+        messages.push(msg);
+      }
+      commands::react_messages(conf, state, queue, limiter, messages).await;
+    }
+    Ok(())
+  };
+  tokio::try_join!(server, dispatcher)?;
+
+  Ok(())
+}
+
+/// Receive updates with getUpdates
+async fn run_polling(conf: &Arc<Config>, state: &Arc<State>, queue: &Arc<JobQueue>, limiter: &Arc<RateLimiter>) -> Result<()> {
+  // getUpdates is refused while webhook is set
+  if let Err(e) = telegram::delete_webhook(&conf.bot_url()).await {
+    log::error!("Error: {}", e);
+  }
+  let mut poller = telegram::Poller::new(
+    conf.bot_url(), conf.poll_timeout,
+    conf.allowed_updates.clone());
+  // ignore everything before start
+  poller.skip_pending().await;
+  loop {
+    let messages = poller.next_batch().await;
+    commands::react_messages(conf, state, queue, limiter, messages).await;
+  }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+  // pretty_env_logger::init_timed();
+  pretty_env_logger::formatted_timed_builder()
+    .write_style(pretty_env_logger::env_logger::WriteStyle::Auto)
+    .filter(Some("ytdlpbot"), log::LevelFilter::Debug)
+    .filter(Some("reqwest"), log::LevelFilter::Info)
+    .init();
 
   let telegram_token = std::env::var("TELEGRAM_TOKEN")
     .expect("Specify TELEGRAM_TOKEN env var.");
+  let telegram_api_url = std::env::var("TELEGRAM_API_URL")
+    .unwrap_or_else(|_| "https://api.telegram.org".to_string());
+  let telegram_local = env_flag("TELEGRAM_API_LOCAL", false);
+  // local Bot API server accepts uploads up to 2000MB
+  let default_max_filesize = if telegram_local { 2000 } else { 50 } * 1024 * 1024;
   let max_filesize : i64 = std::env::var("MAX_FILESIZE")
     .map_err(|x| x.to_string())
     .and_then(|x| x.parse::<i64>().map_err(|x| x.to_string()))
-    .unwrap_or(50 * 1024 * 1024);
-  let conf = config::Config {
+    .unwrap_or(default_max_filesize);
+  let me = telegram::wait_me(&config::bot_url(&telegram_api_url, &telegram_token)).await;
+  let conf = Config {
     max_filesize,
     telegram_token,
+    telegram_api_url,
+    telegram_local,
     download_dir: "dl".to_string(),
+    webhook: webhook_config(),
+    poll_timeout: env_or("POLL_TIMEOUT", 30),
+    allowed_updates: std::env::var("ALLOWED_UPDATES")
+      .unwrap_or_else(|_| "message,callback_query,channel_post".to_string())
+      .split(',')
+      .map(|x| x.trim().to_string())
+      .filter(|x| !x.is_empty())
+      .collect(),
+    workers: env_nonzero("WORKERS", 2),
+    max_user_jobs: env_nonzero("MAX_USER_JOBS", 2),
+    max_jobs: env_nonzero("MAX_JOBS", 20),
+    max_playlist: env_or("MAX_PLAYLIST", 10),
+    bot_username: me.username,
+    bot_id: me.id,
+    settings_per_user: env_flag("SETTINGS_PER_USER", false),
+    rate_burst: env_or("RATE_BURST", 3),
+    rate_refill: env_or("RATE_REFILL", 10),
+    rate_queue: env_or("RATE_QUEUE", 10),
   };
   if !std::fs::metadata(&conf.download_dir).unwrap().is_dir() {
     panic!("Download dir doesn not exist")
   }
-  let state = user_state::State::new();
-  // pretty_env_logger::init_timed();
-  pretty_env_logger::formatted_timed_builder()
-    .write_style(pretty_env_logger::env_logger::WriteStyle::Auto)
-    .filter(Some("ytdlpbot"), log::LevelFilter::Debug)
-    .filter(Some("reqwest"), log::LevelFilter::Info)
-    .init();
+  let conf = Arc::new(conf);
+  let settings_file = std::env::var("SETTINGS_FILE")
+    .unwrap_or_else(|_| "settings.json".to_string());
+  let file_cache_file = std::env::var("FILE_CACHE")
+    .unwrap_or_else(|_| "file_cache.json".to_string());
+  let storage : Box<dyn storage::Storage> = if settings_file.is_empty() {
+    Box::new(storage::MemoryStorage::new(100))
+  } else {
+    Box::new(storage::JsonFileStorage::open(settings_file)
+             .expect("Could not load settings file"))
+  };
+  let files = if file_cache_file.is_empty() {
+    file_cache::FileCache::new()
+  } else {
+    file_cache::FileCache::open(file_cache_file)
+      .expect("Could not load file cache")
+  };
+  let state = State::with_storage(storage, files);
+  let state = Arc::new(state);
+  let queue = JobQueue::new(conf.clone(), state.clone());
+  let limiter = Arc::new(RateLimiter::new(
+    conf.rate_burst, std::time::Duration::from_secs(conf.rate_refill),
+    conf.rate_queue));
   log::info!("Started...");
-  let mut update_id : Option<i64> = None;
-  let mut warm_up = true;
-  loop {
-    let res =
-      telegram::get_updates(&conf.telegram_token, update_id).await;
-    let messages = match res {
-      Ok((update_id_, messages)) => {
-        // log::debug!("update_id={:?}", update_id_);
-        update_id = update_id_.map(|x| x + 1i64);
-        messages
-      },
-      Err(e) => { log::error!("Error: {}", e); vec![] },
-    };
-    // ignore everything before start
-    if warm_up {
-      log::info!("Warmup to updateId = {:?}", update_id);
-      warm_up = false;
-      continue
-    }
-    commands::react_messages(&conf, &state, messages.clone()).await?;
-    tokio::time::sleep(tokio::time::Duration::from_millis(1500)).await
+  match &conf.webhook {
+    Some(webhook) => run_webhook(&conf, &state, &queue, &limiter, webhook).await,
+    None => run_polling(&conf, &state, &queue, &limiter).await,
   }
-
-  // Ok(())
 }
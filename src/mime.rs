@@ -0,0 +1,89 @@
+use std::io::Read;
+
+/// Bytes of file head enough to recognize containers below
+const SNIFF_LEN: usize = 64;
+
+/// Container recognized by magic bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sniffed {
+  pub mime: &'static str,
+  /// Extension telegram expects for this mime type
+  pub ext: &'static str,
+}
+
+impl Sniffed {
+  fn new(mime: &'static str, ext: &'static str) -> Option<Sniffed> {
+    Some(Sniffed {mime, ext})
+  }
+
+  /// Telegram plays it inline when sent as video or audio
+  pub fn playable(&self) -> bool {
+    matches!(self.mime, "video/mp4" | "video/quicktime" | "audio/mp4" | "audio/mpeg")
+  }
+
+  /// Same container holding audio only
+  pub fn audio(self) -> Sniffed {
+    match self.mime {
+      "video/mp4" | "video/quicktime" => Sniffed {mime: "audio/mp4", ext: "m4a"},
+      "video/webm" => Sniffed {mime: "audio/webm", ext: "webm"},
+      "video/x-matroska" => Sniffed {mime: "audio/x-matroska", ext: "mka"},
+      "video/ogg" => Sniffed {mime: "audio/ogg", ext: "ogg"},
+      _ => self,
+    }
+  }
+}
+
+fn contains(data: &[u8], pattern: &[u8]) -> bool {
+  data.windows(pattern.len()).any(|x| x == pattern)
+}
+
+/// MP4 family: major brand tells audio, quicktime and 3gp apart
+fn sniff_ftyp(brand: &[u8]) -> Option<Sniffed> {
+  match brand {
+    b"M4A " | b"M4B " => Sniffed::new("audio/mp4", "m4a"),
+    b"qt  " => Sniffed::new("video/quicktime", "mov"),
+    [b'3', b'g', b'p', _] => Sniffed::new("video/3gpp", "3gp"),
+    [b'3', b'g', b'2', _] => Sniffed::new("video/3gpp2", "3g2"),
+    _ => Sniffed::new("video/mp4", "mp4"),
+  }
+}
+
+/// Recognize container by first bytes of file
+pub fn sniff(data: &[u8]) -> Option<Sniffed> {
+  match data {
+    [_, _, _, _, b'f', b't', b'y', b'p', b0, b1, b2, b3, ..] => sniff_ftyp(&[*b0, *b1, *b2, *b3]),
+    // EBML header, doctype is within first bytes
+    [0x1a, 0x45, 0xdf, 0xa3, ..] if contains(data, b"webm") => Sniffed::new("video/webm", "webm"),
+    [0x1a, 0x45, 0xdf, 0xa3, ..] => Sniffed::new("video/x-matroska", "mkv"),
+    [b'O', b'g', b'g', b'S', ..] if contains(data, b"OpusHead") => Sniffed::new("audio/ogg", "opus"),
+    [b'O', b'g', b'g', b'S', ..] if contains(data, b"theora") => Sniffed::new("video/ogg", "ogv"),
+    [b'O', b'g', b'g', b'S', ..] => Sniffed::new("audio/ogg", "ogg"),
+    [b'f', b'L', b'a', b'C', ..] => Sniffed::new("audio/flac", "flac"),
+    [b'I', b'D', b'3', ..] => Sniffed::new("audio/mpeg", "mp3"),
+    // ADTS frame sync with layer 0
+    [0xff, b1, ..] if b1 & 0xf6 == 0xf0 => Sniffed::new("audio/aac", "aac"),
+    // MPEG audio frame sync
+    [0xff, b1, ..] if b1 & 0xe0 == 0xe0 => Sniffed::new("audio/mpeg", "mp3"),
+    _ => None,
+  }
+}
+
+pub fn sniff_file(path: &str) -> Option<Sniffed> {
+  let mut data = Vec::with_capacity(SNIFF_LEN);
+  std::fs::File::open(path).ok()?
+    .take(SNIFF_LEN as u64)
+    .read_to_end(&mut data).ok()?;
+  sniff(&data)
+}
+
+/// Name to show in telegram: base name of [path] with extension of its content
+pub fn upload_name(path: &str, sniffed: Option<&Sniffed>) -> String {
+  let path = std::path::Path::new(path);
+  let name = path.file_name()
+    .map(|x| x.to_string_lossy().to_string())
+    .unwrap_or_default();
+  match (sniffed, path.file_stem()) {
+    (Some(sniffed), Some(stem)) => format!("{}.{}", stem.to_string_lossy(), sniffed.ext),
+    _ => name,
+  }
+}
@@ -0,0 +1,20 @@
+use crate::telegram_messages::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+/// Prefix of callback data of playlist confirmation buttons
+pub const PREFIX: &str = "playlist:";
+/// Callback data (without prefix) of confirm button
+pub const CONFIRM: &str = "go";
+/// Callback data (without prefix) of dismiss button
+pub const CANCEL: &str = "cancel";
+
+
+fn button(text: String, data: &str) -> InlineKeyboardButton {
+  InlineKeyboardButton {text, callback_data: format!("{}{}", PREFIX, data)}
+}
+
+pub fn keyboard(entries: usize) -> InlineKeyboardMarkup {
+  InlineKeyboardMarkup {inline_keyboard: vec![vec![
+    button(format!("Download {}", entries), CONFIRM),
+    button("Cancel".to_string(), CANCEL),
+  ]]}
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use tokio::sync::watch;
+use crate::config::Config;
+use crate::telegram;
+use crate::utils;
+
+/// Do not edit status message more often than this
+const REPORT_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_secs(3);
+const BAR_WIDTH: usize = 10;
+
+pub type ProgressSender = watch::Sender<Option<Progress>>;
+pub type ProgressReceiver = watch::Receiver<Option<Progress>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+  pub done: u64,
+  pub total: Option<u64>,
+  /// bytes per second
+  pub speed: Option<f64>,
+  /// seconds left
+  pub eta: Option<u64>,
+}
+
+impl Progress {
+  pub fn percent(&self) -> Option<u64> {
+    self.total
+      .filter(|total| *total > 0)
+      .map(|total| std::cmp::min(100, self.done * 100 / total))
+  }
+
+  pub fn bar(&self) -> String {
+    let filled = self.percent().unwrap_or(0) as usize * BAR_WIDTH / 100;
+    format!("{}{}", "▓".repeat(filled), "░".repeat(BAR_WIDTH - filled))
+  }
+}
+
+impl fmt::Display for Progress {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.percent() {
+      Some(percent) => write!(f, "{} {}%", self.bar(), percent)?,
+      None => write!(f, "{}", utils::human_size(self.done as f64))?,
+    };
+    if let Some(speed) = self.speed {
+      write!(f, " {}/s", utils::human_size(speed))?;
+    }
+    if let Some(eta) = self.eta {
+      write!(f, " ETA {}:{:02}", eta / 60, eta % 60)?;
+    }
+    Ok(())
+  }
+}
+
+pub fn channel() -> (ProgressSender, ProgressReceiver) {
+  watch::channel(None)
+}
+
+/// Show progress under [header] in status message until sender is dropped.
+pub async fn report(conf: &Config, chat_id: i64, message_id: i64, header: &str, mut rx: ProgressReceiver) {
+  let mut last_text = String::new();
+  let mut next_edit = tokio::time::Instant::now();
+  while rx.changed().await.is_ok() {
+    if tokio::time::Instant::now() < next_edit {
+      continue;
+    }
+    let progress = rx.borrow().clone();
+    let text = match progress {
+      Some(progress) => format!("{}\n{}", header, progress),
+      None => continue,
+    };
+    if text != last_text {
+      let res = telegram::edit_message_text(
+        &conf.bot_url(), chat_id, message_id, text.clone()).await;
+      if let Err(e) = res {
+        log::error!("Could not report progress: {}", e);
+      }
+      last_text = text;
+      next_edit = tokio::time::Instant::now() + REPORT_INTERVAL;
+    }
+  }
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Drop idle buckets when there are more than this
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+struct Bucket {
+  /// Negative when requests are waiting for tokens
+  tokens: f64,
+  updated: Instant,
+  /// Requests sleeping until their token is refilled
+  waiting: usize,
+}
+
+pub enum Admission {
+  /// Handle request right away
+  Now,
+  /// Handle request after [delay], it is [position] in user's queue
+  Wait {delay: Duration, position: usize},
+  /// User's queue is full
+  Reject,
+}
+
+/// Token bucket per user: [burst] requests at once, then one per [refill].
+pub struct RateLimiter {
+  burst: f64,
+  refill: Duration,
+  max_waiting: usize,
+  buckets: Mutex<HashMap<i64, Bucket>>,
+}
+
+impl RateLimiter {
+  pub fn new(burst: u32, refill: Duration, max_waiting: usize) -> RateLimiter {
+    RateLimiter {burst: burst.max(1) as f64, refill, max_waiting, buckets: Mutex::new(HashMap::new())}
+  }
+
+  fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+    let refilled = (now - bucket.updated).as_secs_f64() / self.refill.as_secs_f64().max(f64::EPSILON);
+    (bucket.tokens + refilled).min(self.burst)
+  }
+
+  /// Take token of user, telling when request may be handled
+  pub fn admit(&self, user_id: i64) -> Admission {
+    let now = Instant::now();
+    let mut buckets = self.buckets.lock().unwrap();
+    if buckets.len() > MAX_IDLE_BUCKETS {
+      buckets.retain(|_, x| x.waiting > 0 || self.refilled(x, now) < self.burst);
+    }
+    let bucket = buckets.entry(user_id)
+      .or_insert(Bucket {tokens: self.burst, updated: now, waiting: 0});
+    let tokens = self.refilled(bucket, now);
+    if tokens < 1.0 && bucket.waiting >= self.max_waiting {
+      return Admission::Reject;
+    }
+    bucket.tokens = tokens - 1.0;
+    bucket.updated = now;
+    if bucket.tokens >= 0.0 {
+      return Admission::Now;
+    }
+    bucket.waiting += 1;
+    let delay = self.refill.mul_f64(-bucket.tokens);
+    Admission::Wait {delay, position: bucket.waiting}
+  }
+
+  /// Waiting request of user is being handled now
+  pub fn done_waiting(&self, user_id: i64) {
+    let mut buckets = self.buckets.lock().unwrap();
+    if let Some(bucket) = buckets.get_mut(&user_id) {
+      bucket.waiting = bucket.waiting.saturating_sub(1);
+    }
+  }
+}
@@ -0,0 +1,113 @@
+use crate::telegram_messages::{InlineKeyboardButton, InlineKeyboardMarkup};
+use crate::user_state::{UserConfig, Mode, Quality, Oversize, CutMode, AudioFormat};
+
+/// Prefix of callback data of settings keyboard buttons
+pub const PREFIX: &str = "settings:";
+
+/// Audio formats offered in menu
+const AUDIO_FORMATS: [AudioFormat; 5] = [AudioFormat::Original, AudioFormat::Mp3, AudioFormat::M4a,
+                                         AudioFormat::Opus, AudioFormat::Flac];
+
+/// Video codecs offered for exclusion in menu
+const VCODECS: [&str; 4] = ["avc1", "vp9", "av01", "hev1"];
+
+
+fn button(text: String, data: String) -> InlineKeyboardButton {
+  InlineKeyboardButton {text, callback_data: format!("{}{}", PREFIX, data)}
+}
+
+/// Button marked as selected when [selected]
+fn choice(label: &str, selected: bool, data: String) -> InlineKeyboardButton {
+  let text = if selected { format!("✓ {}", label) } else { label.to_string() };
+  button(text, data)
+}
+
+fn quality_row(title: &str, key: &str, current: &Quality, options: &[Quality]) -> Vec<InlineKeyboardButton> {
+  options.iter()
+    .map(|q| choice(&format!("{} {:?}", title, q), q == current,
+                    format!("{}:{:?}", key, q)))
+    .collect()
+}
+
+pub fn keyboard(userconf: &UserConfig) -> InlineKeyboardMarkup {
+  let UserConfig {mode, aquality, vquality, vcodec_exclude, oversize, as_file, cut_mode, audio_format, ..} = userconf;
+  let inline_keyboard = vec![
+    vec![
+      choice("Video", *mode == Mode::Video, "mode:Video".to_string()),
+      choice("Audio", *mode == Mode::Audio, "mode:Audio".to_string()),
+    ],
+    quality_row("Video", "vq", vquality,
+                &[Quality::High, Quality::Low, Quality::Awful]),
+    quality_row("Audio", "aq", aquality, &[Quality::High, Quality::Low]),
+    AUDIO_FORMATS.iter()
+      .map(|x| choice(&format!("{:?}", x), x == audio_format, format!("af:{:?}", x)))
+      .collect(),
+    VCODECS.iter()
+      .map(|c| {
+        let excluded = vcodec_exclude.iter().any(|x| x == c);
+        let text = if excluded { format!("✗ {}", c) } else { c.to_string() };
+        button(text, format!("vcx:{}", c))
+      })
+      .collect(),
+    vec![
+      choice("Too big: re-encode", *oversize == Oversize::Reencode,
+             "oversize:Reencode".to_string()),
+      choice("Too big: split", *oversize == Oversize::Split,
+             "oversize:Split".to_string()),
+      choice("Too big: reject", *oversize == Oversize::Reject,
+             "oversize:Reject".to_string()),
+    ],
+    vec![
+      choice("Cut: fast", *cut_mode == CutMode::Fast, "cut:Fast".to_string()),
+      choice("Cut: accurate", *cut_mode == CutMode::Accurate, "cut:Accurate".to_string()),
+    ],
+    vec![
+      choice("Send as media", !*as_file, "file:off".to_string()),
+      choice("Send as file", *as_file, "file:on".to_string()),
+    ],
+    vec![button("Close".to_string(), "close".to_string())],
+  ];
+  InlineKeyboardMarkup {inline_keyboard}
+}
+
+fn parse_quality(s: &str) -> Option<Quality> {
+  match s {
+    "High" => Some(Quality::High),
+    "Low" => Some(Quality::Low),
+    "Awful" => Some(Quality::Awful),
+    _ => None,
+  }
+}
+
+/// Apply button press (callback data without [PREFIX]) to config.
+/// None if data is not recognized.
+pub fn apply(userconf: UserConfig, data: &str) -> Option<UserConfig> {
+  let (key, value) = data.split_once(':')?;
+  match (key, value) {
+    ("mode", "Video") => Some(UserConfig {mode: Mode::Video, .. userconf}),
+    ("mode", "Audio") => Some(UserConfig {mode: Mode::Audio, .. userconf}),
+    ("vq", q) => parse_quality(q).map(
+      |vquality| UserConfig {vquality, .. userconf}),
+    ("aq", q) => parse_quality(q).map(
+      |aquality| UserConfig {aquality, .. userconf}),
+    ("oversize", "Reencode") => Some(UserConfig {oversize: Oversize::Reencode, .. userconf}),
+    ("oversize", "Split") => Some(UserConfig {oversize: Oversize::Split, .. userconf}),
+    ("oversize", "Reject") => Some(UserConfig {oversize: Oversize::Reject, .. userconf}),
+    ("cut", "Fast") => Some(UserConfig {cut_mode: CutMode::Fast, .. userconf}),
+    ("cut", "Accurate") => Some(UserConfig {cut_mode: CutMode::Accurate, .. userconf}),
+    ("af", format) => AudioFormat::parse(format).map(
+      |audio_format| UserConfig {audio_format, .. userconf}),
+    ("file", "on") => Some(UserConfig {as_file: true, .. userconf}),
+    ("file", "off") => Some(UserConfig {as_file: false, .. userconf}),
+    ("vcx", codec) => {
+      let mut vcodec_exclude = userconf.vcodec_exclude.clone();
+      if vcodec_exclude.iter().any(|x| x == codec) {
+        vcodec_exclude.retain(|x| x != codec);
+      } else {
+        vcodec_exclude.push(codec.to_string());
+      }
+      Some(UserConfig {vcodec_exclude, .. userconf})
+    },
+    _ => None,
+  }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use anyhow::{Result, anyhow};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use crate::user_state::UserConfig;
+use crate::utils;
+
+/// Backend keeping user configs for [crate::user_state::State].
+pub trait Storage: Send + Sync {
+  fn get(&self, chat_id: i64) -> Option<UserConfig>;
+  fn put(&mut self, chat_id: i64, val: UserConfig);
+  /// Path and content to write, None if configs are not persisted
+  fn snapshot(&self) -> Result<Option<(String, Vec<u8>)>>;
+}
+
+/// Volatile storage, forgets least recently used configs.
+pub struct MemoryStorage {
+  configs: LruCache<i64, UserConfig>,
+}
+
+impl MemoryStorage {
+  pub fn new(capacity: usize) -> MemoryStorage {
+    let capacity = std::num::NonZeroUsize::new(capacity)
+      .unwrap_or(std::num::NonZeroUsize::new(1).unwrap());
+    MemoryStorage {configs: LruCache::new(capacity)}
+  }
+}
+
+impl Storage for MemoryStorage {
+  fn get(&self, chat_id: i64) -> Option<UserConfig> {
+    self.configs.peek(&chat_id).cloned()
+  }
+
+  fn put(&mut self, chat_id: i64, val: UserConfig) {
+    self.configs.put(chat_id, val);
+  }
+
+  fn snapshot(&self) -> Result<Option<(String, Vec<u8>)>> {
+    Ok(None)
+  }
+}
+
+/// Settings file layout, bump on incompatible change.
+const SCHEMA_VERSION: u64 = 1;
+
+#[derive(Deserialize, Serialize)]
+struct JsonFile {
+  version: u64,
+  configs: HashMap<i64, UserConfig>,
+}
+
+/// Upgrade settings of older [SCHEMA_VERSION]
+fn migrate(version: u64, data: serde_json::Value) -> Result<serde_json::Value> {
+  match version {
+    SCHEMA_VERSION => Ok(data),
+    _ => Err(anyhow!("Unsupported settings schema version {}", version)),
+  }
+}
+
+/// Keeps all configs in memory, whole file is rewritten from [Storage::snapshot].
+pub struct JsonFileStorage {
+  path: String,
+  configs: HashMap<i64, UserConfig>,
+}
+
+impl JsonFileStorage {
+  pub fn open(path: String) -> Result<JsonFileStorage> {
+    let configs = utils::load_json::<JsonFile>(&path, migrate)?
+      .map(|x| x.configs)
+      .unwrap_or_default();
+    log::info!("Loaded {} user configs from {}", configs.len(), path);
+    Ok(JsonFileStorage {path, configs})
+  }
+}
+
+impl Storage for JsonFileStorage {
+  fn get(&self, chat_id: i64) -> Option<UserConfig> {
+    self.configs.get(&chat_id).cloned()
+  }
+
+  fn put(&mut self, chat_id: i64, val: UserConfig) {
+    self.configs.insert(chat_id, val);
+  }
+
+  fn snapshot(&self) -> Result<Option<(String, Vec<u8>)>> {
+    let file = JsonFile {version: SCHEMA_VERSION, configs: self.configs.clone()};
+    Ok(Some((self.path.clone(), serde_json::to_vec_pretty(&file)?)))
+  }
+}
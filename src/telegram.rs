@@ -1,30 +1,59 @@
 // use std::collections::hash_map::HashMap;
 use std::vec::Vec;
+use std::time::Duration;
 use anyhow::{Result, Context, anyhow};
+use futures_util::StreamExt;
+use reqwest::multipart::{Part, Form};
+use crate::progress::{Progress, ProgressSender};
+use crate::mime;
 
 
-fn url_get_updates(token: &String) -> String /* dyn reqwest::IntoUrl */ {
-  format!("https://api.telegram.org/bot{}/getUpdates", token)
+fn url_get_updates(bot_url: &String) -> String /* dyn reqwest::IntoUrl */ {
+  format!("{}/getUpdates", bot_url)
 }
 
-fn url_send_message(token: &String) -> String /* dyn reqwest::IntoUrl */ {
-  format!("https://api.telegram.org/bot{}/sendMessage", token)
+fn url_set_webhook(bot_url: &String) -> String /* dyn reqwest::IntoUrl */ {
+  format!("{}/setWebhook", bot_url)
 }
 
-fn url_delete_message(token: &String) -> String /* dyn reqwest::IntoUrl */ {
-  format!("https://api.telegram.org/bot{}/deleteMessage", token)
+fn url_delete_webhook(bot_url: &String) -> String /* dyn reqwest::IntoUrl */ {
+  format!("{}/deleteWebhook", bot_url)
 }
 
-fn url_edit_message_text(token: &String) -> String /* dyn reqwest::IntoUrl */ {
-  format!("https://api.telegram.org/bot{}/editMessageText", token)
+fn url_get_me(bot_url: &String) -> String /* dyn reqwest::IntoUrl */ {
+  format!("{}/getMe", bot_url)
 }
 
-fn url_send_video(token: &String) -> String /* dyn reqwest::IntoUrl */ {
-  format!("https://api.telegram.org/bot{}/sendVideo", token)
+fn url_send_message(bot_url: &String) -> String /* dyn reqwest::IntoUrl */ {
+  format!("{}/sendMessage", bot_url)
 }
 
-fn url_send_audio(token: &String) -> String /* dyn reqwest::IntoUrl */ {
-  format!("https://api.telegram.org/bot{}/sendAudio", token)
+fn url_delete_message(bot_url: &String) -> String /* dyn reqwest::IntoUrl */ {
+  format!("{}/deleteMessage", bot_url)
+}
+
+fn url_edit_message_text(bot_url: &String) -> String /* dyn reqwest::IntoUrl */ {
+  format!("{}/editMessageText", bot_url)
+}
+
+fn url_edit_message_reply_markup(bot_url: &String) -> String /* dyn reqwest::IntoUrl */ {
+  format!("{}/editMessageReplyMarkup", bot_url)
+}
+
+fn url_answer_callback_query(bot_url: &String) -> String /* dyn reqwest::IntoUrl */ {
+  format!("{}/answerCallbackQuery", bot_url)
+}
+
+fn url_send_video(bot_url: &String) -> String /* dyn reqwest::IntoUrl */ {
+  format!("{}/sendVideo", bot_url)
+}
+
+fn url_send_document(bot_url: &String) -> String /* dyn reqwest::IntoUrl */ {
+  format!("{}/sendDocument", bot_url)
+}
+
+fn url_send_audio(bot_url: &String) -> String /* dyn reqwest::IntoUrl */ {
+  format!("{}/sendAudio", bot_url)
 }
 
 
@@ -33,28 +62,74 @@ use crate::telegram_messages as messages;
 #[derive(Debug, Clone)]
 pub struct IncomeMessage {
   pub chat_id: i64,
+  /// Sender, same as [chat_id] for channel posts
+  pub user_id: i64,
+  /// Chat with user, not group or channel
+  pub private: bool,
   pub username: String,
+  /// Message text or callback data of pressed button
   pub text: String,
+  /// Income message or message with pressed button
+  pub message_id: i64,
+  /// Links found in message text and caption
+  pub urls: Vec<url::Url>,
+  /// Author of message this one replies to
+  pub reply_to_user: Option<i64>,
+  /// Links of message this one replies to
+  pub reply_urls: Vec<url::Url>,
+  /// Set if this is inline keyboard button press
+  pub callback_id: Option<String>,
 }
 
 impl std::fmt::Display for IncomeMessage {
   fn fmt(&self, f:&mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "{}({}): {}", self.username, self.chat_id,
+    let kind = if self.callback_id.is_some() { "callback " } else { "" };
+    write!(f, "{}({}): {}{}", self.username, self.chat_id, kind,
            self.text.replace("\n", "<NL>"))
   }
 }
 
+/// Convert single update to income messages, keeping only text (or captioned)
+/// messages and button presses
+pub fn update_to_messages(update: messages::UpdateMessage) -> Vec<IncomeMessage> {
+  use messages::{Message, Chat, CallbackQuery};
+  let message = update.message.or(update.channel_post).and_then(|message| {
+    let urls = message.urls();
+    let reply_to_user = message.reply_to_message.as_ref()
+      .and_then(|x| x.from.as_ref())
+      .map(|x| x.id);
+    let reply_urls = message.reply_to_message.as_ref()
+      .map_or(vec![], |x| x.urls());
+    let Message {message_id, text, caption, from, chat: Chat {id, username, typ, ..}, ..} = message;
+    let (user_id, username) = match from {
+      Some(from) => (from.id, from.username),
+      None => (id, username),
+    };
+    text.or(caption).map(
+      |text| IncomeMessage {chat_id: id, user_id, private: typ == "private", username, text,
+                            message_id, urls, reply_to_user, reply_urls, callback_id: None})
+  });
+  let callback = update.callback_query.and_then(
+    |CallbackQuery {id, from, message, data}| {
+      let Message {message_id, chat, ..} = message?;
+      Some(IncomeMessage {chat_id: chat.id, user_id: from.id, private: chat.typ == "private",
+                          username: from.username, text: data?, message_id, urls: vec![],
+                          reply_to_user: None, reply_urls: vec![], callback_id: Some(id)})
+    });
+  message.into_iter().chain(callback).collect()
+}
+
 /// Return (Option<update_id>, vec![IncomeMessage])
 pub async fn get_updates(
-  token: &String, offset: Option<i64>)
+  bot_url: &String, offset: Option<i64>, limit: Option<i64>,
+  timeout: u64, allowed_updates: &[String])
   -> Result<(Option<i64>, Vec<IncomeMessage>)> {
-  let url = url_get_updates(token);
-  // json::<serde_json::Value>
-  let request = reqwest::Client::new().get(url);
-  let request = match offset {
-    None => request,
-    Some(offset) => request.query(&[("offset", offset)]),
-  };
+  let url = url_get_updates(bot_url);
+  let data = messages::GetUpdatesRequest {
+    offset, limit, timeout, allowed_updates: allowed_updates.to_vec()};
+  // server holds request for up to [timeout] seconds
+  let request = reqwest::Client::new().post(url).json(&data)
+    .timeout(std::time::Duration::from_secs(timeout + 10));
   let res = request.send().await?;
   let data = res.bytes().await?;
   // log::debug!("called get_updates: parsing respnse: {:#?}", &data);
@@ -62,125 +137,497 @@ pub async fn get_updates(
   // log::debug!("GetUpdates: {:#?}", res1);
   let res = serde_json::from_slice::<messages::GetUpdates>(&data)
     .context(format!("Could not parse GetUpdates request: {:#?}", res1))?;
-  // use messages;
-  use messages::{Message, Chat};
+  if !res.ok {
+    return Err(messages::TelegramError::new(
+      res.error_code, &res.description, res.parameters.as_ref()).into());
+  }
   let update_id = res.max_update_id();
-  let t2 = res.to_messages();
-  let t2 = t2.iter().filter_map(
-    |Message {text, chat: Chat {id, username, ..}, ..}|
-    if let Some(text) = text {
-      Some(IncomeMessage {chat_id:*id, username:username.clone(), text: text.clone()})
-    } else {
-      None
-    })
+  let t2 = res.result.into_iter()
+    .filter_map(|x| serde_json::from_value::<messages::UpdateMessage>(x.clone())
+                .map_err(|e| log::error!("Skipping update {}: {}", x, e))
+                .ok())
+    .flat_map(update_to_messages)
     .collect::<Vec<_>>();
   // log::debug!("{:#?}", t2);
   Ok((update_id, t2))
 }
 
-pub async fn send_message(
-  token: &String, chat_id: i64, text: String)
+/// Bot API error behind [e], if it is one
+pub fn telegram_error(e: &anyhow::Error) -> Option<&messages::TelegramError> {
+  e.downcast_ref::<messages::TelegramError>()
+}
+
+fn is_not_modified(e: &anyhow::Error) -> bool {
+  telegram_error(e).is_some_and(|x| x.is_not_modified())
+}
+
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Long polling getUpdates loop state: keeps offset and error backoff.
+pub struct Poller {
+  bot_url: String,
+  offset: Option<i64>,
+  timeout: u64,
+  allowed_updates: Vec<String>,
+  backoff: Duration,
+}
+
+impl Poller {
+  pub fn new(bot_url: String, timeout: u64, allowed_updates: Vec<String>) -> Poller {
+    Poller {bot_url, offset: None, timeout, allowed_updates, backoff: BACKOFF_MIN}
+  }
+
+  /// Confirm everything received before start, so it is not processed.
+  /// Skip updates sent before start, retrying with backoff on errors.
+  pub async fn skip_pending(&mut self) {
+    // offset=-1 returns only the last update
+    let (update_id, _) = self.poll(Some(-1), Some(1), 0).await;
+    if let Some(update_id) = update_id {
+      self.offset = Some(update_id + 1);
+    }
+    log::info!("Warmup to updateId = {:?}", self.offset);
+  }
+
+  /// Wait for next batch of messages, retrying with backoff on errors.
+  pub async fn next_batch(&mut self) -> Vec<IncomeMessage> {
+    loop {
+      let (update_id, messages) = self.poll(self.offset, None, self.timeout).await;
+      if let Some(update_id) = update_id {
+        self.offset = Some(update_id + 1);
+      }
+      if !messages.is_empty() {
+        return messages;
+      }
+    }
+  }
+
+  /// Call getUpdates until it succeeds
+  async fn poll(&mut self, offset: Option<i64>, limit: Option<i64>, timeout: u64) -> (Option<i64>, Vec<IncomeMessage>) {
+    loop {
+      let res = get_updates(
+        &self.bot_url, offset, limit, timeout,
+        &self.allowed_updates).await;
+      match res {
+        Ok(res) => {
+          self.backoff = BACKOFF_MIN;
+          return res;
+        },
+        Err(e) => {
+          let delay = telegram_error(&e)
+            .and_then(|x| x.retry_after)
+            .map_or(self.backoff, Duration::from_secs);
+          log::error!("getUpdates error: {}, retry in {:?}", e, delay);
+          tokio::time::sleep(delay).await;
+          self.backoff = std::cmp::min(self.backoff * 2, BACKOFF_MAX);
+        },
+      }
+    }
+  }
+}
+
+/// Repeats of flood-waited or failed on server side request
+const RETRY_MAX: u32 = 5;
+
+/// Send request and parse response, turning `ok: false` into [messages::TelegramError].
+/// Flood wait and transient server errors are retried, unless request body is a stream.
+async fn call<R>(method: &str, mut request: reqwest::RequestBuilder) -> Result<R>
+where R: serde::de::DeserializeOwned + messages::ApiResponse {
+  let mut attempt = 0;
+  let mut backoff = BACKOFF_MIN;
+  loop {
+    let next = request.try_clone();
+    let res = request.send().await?;
+    let status = res.status();
+    let data = res.bytes().await?;
+    let error = match serde_json::from_slice::<R>(&data) {
+      Ok(res) => match res.error() {
+        None => return Ok(res),
+        Some(error) => error,
+      },
+      // proxy in front of Bot API may answer with html
+      Err(_) if status.is_server_error() =>
+        messages::TelegramError::new(
+          Some(status.as_u16() as i64), &String::from_utf8_lossy(&data), None),
+      Err(e) => return Err(anyhow::Error::from(e)
+                           .context(format!("Could not parse {} response", method))),
+    };
+    let delay = match error.retry_after {
+      Some(retry_after) => Duration::from_secs(retry_after),
+      None => backoff,
+    };
+    match next {
+      Some(next) if error.is_transient() && attempt < RETRY_MAX => {
+        log::warn!("{} failed: {}, retry in {:?}", method, error, delay);
+        tokio::time::sleep(delay).await;
+        backoff = std::cmp::min(backoff * 2, BACKOFF_MAX);
+        attempt += 1;
+        request = next;
+      },
+      _ => return Err(error.into()),
+    }
+  }
+}
+
+/// Send message as reply to [reply_to] if set
+pub async fn send_reply(
+  bot_url: &String, chat_id: i64, reply_to: Option<i64>, text: String)
+  -> Result<messages::SendMessageResponse> {
+  send_message_markup(bot_url, chat_id, text, reply_to, None).await
+}
+
+pub async fn send_message_markup(
+  bot_url: &String, chat_id: i64, text: String, reply_to: Option<i64>,
+  reply_markup: Option<messages::InlineKeyboardMarkup>)
   -> Result<messages::SendMessageResponse> {
   log::info!("Send to {}: {}", chat_id, &text);
-  let url = url_send_message(token);
-  let data = messages::SendMessage {chat_id, text, disable_notification: false, disable_web_page_preview: true};
+  let url = url_send_message(bot_url);
+  let data = messages::SendMessage {
+    chat_id, text, disable_notification: false, disable_web_page_preview: true,
+    reply_to_message_id: reply_to, allow_sending_without_reply: true, reply_markup};
   let client = reqwest::Client::new();
-  let res = client.post(url).json(&data).send().await?;
-  // let res = res.json::<serde_json::Value>().await?;
-  let data = res.bytes().await?;
-  log::debug!("DBG: {:#?}", &data);
-  let res = serde_json::from_slice::<messages::SendMessageResponse>(&data)?;
-  // let res = res.json::<messages::SendMessageResponse>().await?;
+  let res : messages::SendMessageResponse =
+    call("sendMessage", client.post(url).json(&data)).await?;
   log::debug!("{}", res);
   
   Ok(res)
 }
 
+pub async fn edit_message_reply_markup(
+  bot_url: &String, chat_id: i64, message_id: i64,
+  reply_markup: messages::InlineKeyboardMarkup)
+  -> Result<()> {
+  let url = url_edit_message_reply_markup(bot_url);
+  let data = messages::EditMessageReplyMarkup {chat_id, message_id, reply_markup};
+  let client = reqwest::Client::new();
+  let res = call::<messages::SendMessageResponse>(
+    "editMessageReplyMarkup", client.post(url).json(&data)).await;
+  match res {
+    Err(e) if is_not_modified(&e) => Ok(()),
+    res => res.map(|_| ()),
+  }
+}
+
+pub async fn answer_callback_query(
+  bot_url: &String, callback_query_id: String, text: Option<String>)
+  -> Result<()> {
+  let url = url_answer_callback_query(bot_url);
+  let data = messages::AnswerCallbackQuery {callback_query_id, text};
+  let client = reqwest::Client::new();
+  call::<messages::BoolResponse>(
+    "answerCallbackQuery", client.post(url).json(&data)).await
+    .context("Could not answer callback query")?;
+
+  Ok(())
+}
+
 pub async fn delete_message(
-  token: &String, chat_id: i64, message_id: i64)
+  bot_url: &String, chat_id: i64, message_id: i64)
   -> Result<()> {
-  let url = url_delete_message(token);
+  let url = url_delete_message(bot_url);
   let data = messages::DeleteMessage {chat_id, message_id};
   let client = reqwest::Client::new();
-  let res = client.post(url).json(&data).send().await?;
-  let res = res.json::<serde_json::Value>().await?;
-  // let res = res.json::<messages::SendMessageResponse>().await?;
-  log::debug!("delete response for {}: {}", message_id, res);
-  
-  Ok(())
+  let res = call::<messages::BoolResponse>(
+    "deleteMessage", client.post(url).json(&data)).await;
+  match res {
+    Err(e) if telegram_error(&e).is_some_and(|x| x.is_message_gone()) => {
+      log::debug!("Message {} is already deleted", message_id);
+      Ok(())
+    },
+    res => res.map(|_| ()),
+  }
 }
 
 pub async fn edit_message_text(
-  token: &String, chat_id: i64, message_id: i64, text: String)
-  -> Result<messages::SendMessageResponse> {
+  bot_url: &String, chat_id: i64, message_id: i64, text: String)
+  -> Result<()> {
+  edit_message_text_markup(bot_url, chat_id, message_id, text, None).await
+}
+
+/// Edit text and replace inline keyboard (removed if [reply_markup] is None)
+pub async fn edit_message_text_markup(
+  bot_url: &String, chat_id: i64, message_id: i64, text: String,
+  reply_markup: Option<messages::InlineKeyboardMarkup>)
+  -> Result<()> {
   // log::info!("Edit for {}: {}", chat_id, &text);
-  let url = url_edit_message_text(token);
-  let data = messages::EditMessageText {chat_id, message_id, text, disable_web_page_preview: true};
+  let url = url_edit_message_text(bot_url);
+  let data = messages::EditMessageText {chat_id, message_id, text, disable_web_page_preview: true, reply_markup};
   let client = reqwest::Client::new();
-  let res = client.post(url).json(&data).send().await?;
-  // let res = res.json::<serde_json::Value>().await?;
-  let res = res.json::<messages::SendMessageResponse>().await?;
+  let res = call::<messages::SendMessageResponse>(
+    "editMessageText", client.post(url).json(&data)).await;
+  match res {
+    Err(e) if is_not_modified(&e) => Ok(()),
+    res => res.map(|_| ()),
+  }
+}
+
+/// Multipart part streaming file, reporting sent bytes to [progress]
+async fn file_part(path: &String, progress: ProgressSender) -> Result<Part> {
+  let file = tokio::fs::File::open(path).await?;
+  let total = file.metadata().await?.len();
+  let stream = tokio_util::codec::FramedRead::new(
+    file, tokio_util::codec::BytesCodec::new());
+  let started = std::time::Instant::now();
+  let mut done = 0u64;
+  let stream = stream.inspect(move |chunk| {
+    if let Ok(chunk) = chunk {
+      done += chunk.len() as u64;
+      let elapsed = started.elapsed().as_secs_f64();
+      let speed = (elapsed > 0.0).then(|| done as f64 / elapsed);
+      let eta = speed.filter(|x| *x > 0.0)
+        .map(|x| ((total - done) as f64 / x) as u64);
+      let _ = progress.send(Some(Progress {done, total: Some(total), speed, eta}));
+    }
+  });
+  Ok(Part::stream_with_length(reqwest::Body::wrap_stream(stream), total))
+}
+
+/// file:// URI of file, local Bot API server reads it from disk directly
+fn local_file_uri(path: &String) -> Result<String> {
+  let path = std::fs::canonicalize(path)?;
+  let uri = url::Url::from_file_path(&path)
+    .map_err(|_| anyhow!("Could not make file uri of {:?}", path))?;
+  Ok(uri.to_string())
+}
+
+/// Media to send
+#[derive(Debug, Clone)]
+pub enum InputFile {
+  /// Upload file with multipart request
+  Upload(String),
+  /// Pass file:// path of file to local Bot API server
+  Local(String),
+  /// Resend file already stored on telegram servers
+  FileId(String),
+}
+
+impl std::fmt::Display for InputFile {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      InputFile::Upload(path) => write!(f, "{}", path),
+      InputFile::Local(path) => write!(f, "local {}", path),
+      InputFile::FileId(file_id) => write!(f, "file_id {}", file_id),
+    }
+  }
+}
+
+/// Optional attributes of sent media
+#[derive(Debug, Clone, Default)]
+pub struct MediaMeta {
+  /// Seconds
+  pub duration: Option<i64>,
+  pub width: Option<i64>,
+  pub height: Option<i64>,
+  pub performer: Option<String>,
+  pub title: Option<String>,
+  /// Path of jpeg preview
+  pub thumbnail: Option<String>,
+  /// Downloaded in audio mode: upload is named and typed as audio whatever method sends it
+  pub audio: bool,
+}
+
+/// Send [request] with [file] attached as [field], uploads are named and
+/// typed after their content, as audio if [MediaMeta::audio]
+async fn send_file(
+  request: reqwest::RequestBuilder, field: &str, file: InputFile,
+  meta: &MediaMeta, progress: ProgressSender)
+  -> Result<messages::SendMessageResponse> {
+  // stored file keeps its thumbnail
+  let thumbnail = match file {
+    InputFile::FileId(_) => None,
+    _ => meta.thumbnail.clone(),
+  };
+  let mut form = match thumbnail {
+    Some(thumbnail) => {
+      let part = Part::bytes(tokio::fs::read(&thumbnail).await?)
+        .file_name("thumbnail.jpg")
+        .mime_str("image/jpeg")?;
+      Some(Form::new().part("thumbnail", part))
+    },
+    None => None,
+  };
+  let request = match file {
+    InputFile::FileId(file_id) =>
+      request.query(&[(field, file_id)]),
+    InputFile::Local(path) =>
+      request.query(&[(field, local_file_uri(&path)?)]),
+    InputFile::Upload(path) => {
+      let sniffed = mime::sniff_file(&path)
+        .map(|x| if meta.audio { x.audio() } else { x });
+      log::debug!("Sniffed {}: {:?}", path, sniffed);
+      let part = file_part(&path, progress).await?
+        .file_name(mime::upload_name(&path, sniffed.as_ref()));
+      let part = match sniffed {
+        Some(sniffed) => part.mime_str(sniffed.mime)?,
+        None => part,
+      };
+      let data = form.take().unwrap_or_default().part(field.to_string(), part);
+      request.multipart(data)
+    },
+  };
+  let request = match form {
+    Some(form) => request.multipart(form),
+    None => request,
+  };
+  // uploads are not retried: streamed body can not be sent twice
+  let res : messages::SendMessageResponse = call(field, request).await?;
+  // {"description":"Request Entity Too Large","error_code":413,"ok":false}
   log::debug!("{}", res);
-  
   Ok(res)
 }
 
-pub async fn send_video(
-  token: &String, chat_id: i64, caption: String, video: String)
-  -> Result<()> {
-  log::info!("Send video to {}: {}", chat_id, video);
-  let url = url_send_video(token);
-  let request = reqwest::Client::new().post(url).query(&[
+/// Query of sendVideo/sendAudio/sendDocument common to all media
+fn media_query(chat_id: i64, caption: String, reply_to: Option<i64>) -> Vec<(&'static str, String)> {
+  let mut query = vec![
     ("chat_id", chat_id.to_string()),
     ("caption", caption),
-  ]);
-  let file = tokio::fs::File::open(&video).await?;
-  let stream = tokio_util::codec::FramedRead::new(
-    file, tokio_util::codec::BytesCodec::new());
-  use reqwest::multipart::{Part, Form};
-  let filename_ext = video.split(".").last().unwrap_or("mp4");
-  let mime = format!("video/{}", filename_ext);
-  let part = Part::stream(reqwest::Body::wrap_stream(stream))
-    .file_name(video.clone())
-    .mime_str(mime.as_str())?;
-  // todo: use libmagic to set mime type
-  let data = Form::new().part("video", part);
-  let res = request.multipart(data).send().await?;
-  // let res = res.json::<serde_json::Value>().await?;
-  let res = res.json::<messages::SendMessageResponse>().await
-    .context("Could not parse sendVideo response")?;
-  log::debug!("{}", res);
+  ];
+  if let Some(reply_to) = reply_to {
+    query.push(("reply_to_message_id", reply_to.to_string()));
+    query.push(("allow_sending_without_reply", "true".to_string()));
+  }
+  query
+}
+
+/// File as telegram stored it
+#[derive(Debug, Clone)]
+pub struct SentFile {
+  pub file_id: String,
+  /// Kept as document, not playable media
+  pub document: bool,
+}
+
+/// Stored file of sent media message
+fn sent_file(res: messages::SendMessageResponse) -> Option<SentFile> {
+  let result = res.result?;
+  let media = result.video.map(|x| x.file_id)
+    .or(result.audio.map(|x| x.file_id));
+  match (media, result.document) {
+    (Some(file_id), _) => Some(SentFile {file_id, document: false}),
+    (None, Some(document)) => Some(SentFile {file_id: document.file_id, document: true}),
+    (None, None) => None,
+  }
+}
+
+/// Send video, return stored file.
+pub async fn send_video(
+  bot_url: &String, chat_id: i64, reply_to: Option<i64>, caption: String,
+  video: InputFile, meta: &MediaMeta, progress: ProgressSender)
+  -> Result<Option<SentFile>> {
+  log::info!("Send video to {}: {}", chat_id, video);
+  let url = url_send_video(bot_url);
+  let mut query = media_query(chat_id, caption, reply_to);
+  let attrs = [("duration", meta.duration), ("width", meta.width), ("height", meta.height)];
+  for (name, value) in attrs {
+    if let Some(value) = value {
+      query.push((name, value.to_string()));
+    }
+  }
+  query.push(("supports_streaming", "true".to_string()));
+  let request = reqwest::Client::new().post(url).query(&query);
+  let res = send_file(request, "video", video, meta, progress).await?;
   if !res.is_ok() {
     return Err(anyhow!("Could not send Video: {}", res.description));
   }
   
-  Ok(())
+  Ok(sent_file(res))
 }
 
+/// Send audio, return stored file.
 pub async fn send_audio(
-  token: &String, chat_id: i64, caption: String, audio: String)
-  -> Result<()> {
+  bot_url: &String, chat_id: i64, reply_to: Option<i64>, caption: String,
+  audio: InputFile, meta: &MediaMeta, progress: ProgressSender)
+  -> Result<Option<SentFile>> {
   log::info!("Send audio to {}: {}", chat_id, audio);
-  let url = url_send_audio(token);
-  let request = reqwest::Client::new().post(url).query(&[
-    ("chat_id", chat_id.to_string()),
-    ("caption", caption),
-  ]);
-  let file = tokio::fs::File::open(audio.clone()).await?;
-  let stream = tokio_util::codec::FramedRead::new(
-    file, tokio_util::codec::BytesCodec::new());
-  use reqwest::multipart::{Part, Form};
-  let part = Part::stream(reqwest::Body::wrap_stream(stream))
-    .file_name(audio);
-    // .mime_str(format!("audio/{}", ext).as_str())?;
-  // todo: use libmagic to set mime type
-  let data = Form::new().part("audio", part);
-  let res = request.multipart(data).send().await?;
-  // let res = res.json::<serde_json::Value>().await?;
-  // {"description":"Request Entity Too Large","error_code":413,"ok":false}
-  let res = res.json::<messages::SendMessageResponse>().await
-    .context("Could not parse sendAudio response")?;
-  log::debug!("{}", res);
+  let url = url_send_audio(bot_url);
+  let mut query = media_query(chat_id, caption, reply_to);
+  if let Some(duration) = meta.duration {
+    query.push(("duration", duration.to_string()));
+  }
+  if let Some(performer) = &meta.performer {
+    query.push(("performer", performer.clone()));
+  }
+  if let Some(title) = &meta.title {
+    query.push(("title", title.clone()));
+  }
+  let request = reqwest::Client::new().post(url).query(&query);
+  let res = send_file(request, "audio", audio, meta, progress).await?;
+  if !res.is_ok() {
+    return Err(anyhow!("Could not send Audio: {}", res.description));
+  }
   
+  Ok(sent_file(res))
+}
+
+/// Send any file without inline player, return stored file.
+pub async fn send_document(
+  bot_url: &String, chat_id: i64, reply_to: Option<i64>, caption: String,
+  document: InputFile, meta: &MediaMeta, progress: ProgressSender)
+  -> Result<Option<SentFile>> {
+  log::info!("Send document to {}: {}", chat_id, document);
+  let url = url_send_document(bot_url);
+  let request = reqwest::Client::new().post(url)
+    .query(&media_query(chat_id, caption, reply_to));
+  let res = send_file(request, "document", document, meta, progress).await?;
+  if !res.is_ok() {
+    return Err(anyhow!("Could not send Document: {}", res.description));
+  }
+
+  Ok(sent_file(res))
+}
+
+/// Bot's own user, to recognize mentions and replies in groups
+pub async fn get_me(bot_url: &String) -> Result<messages::From> {
+  let url = url_get_me(bot_url);
+  let res = reqwest::Client::new().get(url).send().await?;
+  let res = res.json::<messages::GetMe>().await
+    .context("Could not parse getMe response")?;
+  if !res.ok {
+    return Err(anyhow!("getMe failed: {}", res.description));
+  }
+  res.result.ok_or(anyhow!("getMe returned no user"))
+}
+
+/// Bot user, retrying with backoff until Bot API answers
+pub async fn wait_me(bot_url: &String) -> messages::From {
+  let mut backoff = BACKOFF_MIN;
+  loop {
+    match get_me(bot_url).await {
+      Ok(me) => return me,
+      Err(e) => {
+        log::error!("getMe error: {}, check TELEGRAM_TOKEN, retry in {:?}", e, backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = std::cmp::min(backoff * 2, BACKOFF_MAX);
+      },
+    }
+  }
+}
+
+pub async fn set_webhook(
+  bot_url: &String, url: String, secret_token: String,
+  allowed_updates: Vec<String>)
+  -> Result<()> {
+  log::info!("Set webhook to {}", &url);
+  let data = messages::SetWebhook {
+    url, secret_token,
+    drop_pending_updates: true,
+    allowed_updates};
+  let client = reqwest::Client::new();
+  let res = call::<messages::BoolResponse>(
+    "setWebhook", client.post(url_set_webhook(bot_url)).json(&data)).await
+    .context("Could not set webhook")?;
+  log::debug!("setWebhook response: {:?}", res);
+
+  Ok(())
+}
+
+pub async fn delete_webhook(bot_url: &String) -> Result<()> {
+  let data = messages::DeleteWebhook {drop_pending_updates: false};
+  let client = reqwest::Client::new();
+  let res = call::<messages::BoolResponse>(
+    "deleteWebhook", client.post(url_delete_webhook(bot_url)).json(&data)).await
+    .context("Could not delete webhook")?;
+  log::debug!("deleteWebhook response: {:?}", res);
+
   Ok(())
 }
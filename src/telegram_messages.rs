@@ -34,7 +34,12 @@ fn unknown() -> String {
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Chat {
+  /// Private chats only
+  #[serde(default)]
   pub first_name: String,
+  /// Groups and channels only
+  #[serde(default)]
+  pub title: String,
   pub id: i64,
   #[serde(default)]
   pub last_name: String,
@@ -57,25 +62,121 @@ pub struct From {
   pub username: String,
 }
 
+/// Special part of text: link, mention, command etc.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MessageEntity {
+  #[serde(rename="type")]
+  pub typ: String,
+  /// In UTF-16 code units
+  pub offset: usize,
+  /// In UTF-16 code units
+  pub length: usize,
+  /// Target of "text_link"
+  #[serde(default)]
+  pub url: Option<String>,
+}
+
+impl MessageEntity {
+  /// Substring of [text] this entity covers
+  pub fn slice(&self, text: &str) -> Option<String> {
+    let utf16 : Vec<u16> = text.encode_utf16().collect();
+    let part = utf16.get(self.offset..self.offset + self.length)?;
+    String::from_utf16(part).ok()
+  }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Message {
   pub message_id: i64,
   pub text: Option<String>,
+  #[serde(default)]
+  pub entities: Vec<MessageEntity>,
+  /// Text of media message
+  #[serde(default)]
+  pub caption: Option<String>,
+  #[serde(default)]
+  pub caption_entities: Vec<MessageEntity>,
   // #[serde(deserialize_with="datetime_of_timestamp")]
   // date: DateTime<Utc>,
   pub date: i64,
   pub chat: Chat,
-  pub from: From,
+  /// Empty for channel posts
+  #[serde(default)]
+  pub from: Option<From>,
+  #[serde(default)]
+  pub reply_to_message: Option<Box<Message>>,
+}
+
+impl Message {
+  /// Links of text and caption in order of appearance, without duplicates.
+  /// Words looking like http(s) URLs are taken if there are no link entities.
+  pub fn urls(&self) -> Vec<url::Url> {
+    let parts = [(&self.text, &self.entities), (&self.caption, &self.caption_entities)];
+    let mut urls : Vec<url::Url> = vec![];
+    for (text, entities) in parts {
+      let text = match text {
+        Some(text) => text,
+        None => continue,
+      };
+      let mut found : Vec<_> = entities.iter()
+        .filter_map(|entity| match entity.typ.as_str() {
+          "url" => entity.slice(text),
+          "text_link" => entity.url.clone(),
+          _ => None,
+        })
+        .filter_map(|x| parse_link(&x))
+        .collect();
+      if found.is_empty() {
+        found = text.split_whitespace()
+          .filter(|x| x.starts_with("http://") || x.starts_with("https://"))
+          .filter_map(parse_link)
+          .collect();
+      }
+      for url in found {
+        if !urls.contains(&url) {
+          urls.push(url);
+        }
+      }
+    }
+    urls
+  }
+}
+
+/// Parse link, adding scheme telegram omits for "url" entities like "youtu.be/..."
+fn parse_link(s: &str) -> Option<url::Url> {
+  let url = if s.contains("://") {
+    url::Url::parse(s).ok()?
+  } else {
+    url::Url::parse(&format!("https://{}", s)).ok()?
+  };
+  match url.scheme() {
+    "http" | "https" if url.host().is_some() => Some(url),
+    _ => None,
+  }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct MyChatMember {}
 
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CallbackQuery {
+  pub id: String,
+  pub from: From,
+  #[serde(default)]
+  pub message: Option<Message>,
+  #[serde(default)]
+  pub data: Option<String>,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct UpdateMessage {
   #[serde(default)]
   pub message: Option<Message>,
   #[serde(default)]
+  pub channel_post: Option<Message>,
+  #[serde(default)]
+  pub callback_query: Option<CallbackQuery>,
+  #[serde(default)]
   pub my_chat_member: Option<MyChatMember>,
   pub update_id: i64
 }
@@ -83,30 +184,73 @@ pub struct UpdateMessage {
 #[derive(Deserialize, Serialize, Debug)]
 pub struct GetUpdates {
   pub ok: bool,
-  pub result: Vec<UpdateMessage>,
+  #[serde(default)]
+  pub error_code: Option<i64>,
+  #[serde(default)]
+  pub description: String,
+  #[serde(default)]
+  pub parameters: Option<ResponseParameters>,
+  /// Raw updates, parsed one by one so that unknown one does not block the rest
+  #[serde(default)]
+  pub result: Vec<serde_json::Value>,
 }
 
-impl GetUpdates {
-  pub fn to_messages(&self) -> Vec<Message> {
-    self.result.iter().filter_map(|UpdateMessage {message, ..}|
-                                  if let Some(Message {text: Some(_), ..}) = message {
-                                    message.clone()
-                                  } else { None }).collect()
-  }
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GetUpdatesRequest {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub offset: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub limit: Option<i64>,
+  pub timeout: u64,
+  pub allowed_updates: Vec<String>,
+}
 
+impl GetUpdates {
   pub fn max_update_id(&self) -> Option<i64> {
-    self.result.iter().map(
-      |UpdateMessage {update_id, ..}| *update_id).max()
+    self.result.iter()
+      .filter_map(|x| x["update_id"].as_i64())
+      .max()
   }
 }
 
 
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct InlineKeyboardButton {
+  pub text: String,
+  pub callback_data: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct InlineKeyboardMarkup {
+  pub inline_keyboard: Vec<Vec<InlineKeyboardButton>>,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct SendMessage {
   pub chat_id: i64,
   pub text: String,
   pub disable_notification: bool,
   pub disable_web_page_preview: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub reply_to_message_id: Option<i64>,
+  /// Send anyway if message to reply to is deleted
+  pub allow_sending_without_reply: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub reply_markup: Option<InlineKeyboardMarkup>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct EditMessageReplyMarkup {
+  pub chat_id: i64,
+  pub message_id: i64,
+  pub reply_markup: InlineKeyboardMarkup,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct AnswerCallbackQuery {
+  pub callback_query_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub text: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -121,11 +265,139 @@ pub struct EditMessageText {
   pub message_id: i64,
   pub text: String,
   pub disable_web_page_preview: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub reply_markup: Option<InlineKeyboardMarkup>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SetWebhook {
+  pub url: String,
+  pub secret_token: String,
+  pub drop_pending_updates: bool,
+  pub allowed_updates: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct DeleteWebhook {
+  pub drop_pending_updates: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GetMe {
+  pub ok: bool,
+  #[serde(default)]
+  pub description: String,
+  #[serde(default)]
+  pub result: Option<From>,
+}
+
+/// Details of failed request
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ResponseParameters {
+  /// Seconds to wait before repeating request exceeding flood control
+  #[serde(default)]
+  pub retry_after: Option<u64>,
+  /// Group was upgraded to supergroup with this id
+  #[serde(default)]
+  pub migrate_to_chat_id: Option<i64>,
+}
+
+/// Error of `ok: false` response
+#[derive(Debug, Clone)]
+pub struct TelegramError {
+  pub error_code: i64,
+  pub description: String,
+  pub retry_after: Option<u64>,
+  pub migrate_to_chat_id: Option<i64>,
+}
+
+impl TelegramError {
+  pub fn new(error_code: Option<i64>, description: &str, parameters: Option<&ResponseParameters>) -> TelegramError {
+    let parameters = parameters.cloned().unwrap_or_default();
+    TelegramError {
+      error_code: error_code.unwrap_or(0),
+      description: description.to_string(),
+      retry_after: parameters.retry_after,
+      migrate_to_chat_id: parameters.migrate_to_chat_id,
+    }
+  }
+
+  /// Edit with same text and markup, nothing to do
+  pub fn is_not_modified(&self) -> bool {
+    self.description.contains("message is not modified")
+  }
+
+  /// Message to edit or delete does not exist anymore
+  pub fn is_message_gone(&self) -> bool {
+    self.description.contains("message to delete not found")
+      || self.description.contains("message to edit not found")
+  }
+
+  /// Request was refused as is, e.g. media of unsupported format
+  pub fn is_bad_request(&self) -> bool {
+    self.error_code == 400
+  }
+
+  /// Media itself is refused (type, format, dimensions), it may pass as document
+  pub fn is_media_rejected(&self) -> bool {
+    let description = self.description.to_lowercase();
+    self.is_bad_request()
+      && ["wrong type", "type of the", "content type", "file type", "unsupported",
+          "media_empty", "invalid media", "dimensions"]
+      .iter().any(|x| description.contains(x))
+  }
+
+  /// Worth repeating request later
+  pub fn is_transient(&self) -> bool {
+    self.retry_after.is_some() || self.error_code >= 500
+  }
+}
+
+impl fmt::Display for TelegramError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Telegram error {}: {}", self.error_code, self.description)?;
+    if let Some(retry_after) = self.retry_after {
+      write!(f, " (retry after {}s)", retry_after)?;
+    }
+    if let Some(chat_id) = self.migrate_to_chat_id {
+      write!(f, " (migrated to chat {})", chat_id)?;
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for TelegramError {}
+
+/// Response telling whether request succeeded
+pub trait ApiResponse {
+  fn error(&self) -> Option<TelegramError>;
+}
+
+/// Response for methods returning plain True on success
+#[derive(Deserialize, Serialize, Debug)]
+pub struct BoolResponse {
+  pub ok: bool,
+  #[serde(default)]
+  pub error_code: Option<i64>,
+  #[serde(default)]
+  pub description: String,
+  #[serde(default)]
+  pub parameters: Option<ResponseParameters>,
+  #[serde(default)]
+  pub result: Option<bool>,
+}
+
+impl ApiResponse for BoolResponse {
+  fn error(&self) -> Option<TelegramError> {
+    (!self.ok).then(|| TelegramError::new(
+      self.error_code, &self.description, self.parameters.as_ref()))
+  }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Thumb {
   pub file_id: String,
+  #[serde(default)]
   pub file_size: i64,
   pub file_unique_id: String,
   pub height: i64,
@@ -136,11 +408,15 @@ pub struct Thumb {
 pub struct Video {
   pub duration: i64,
   pub file_id: String,
+  #[serde(default)]
   pub file_name: String,
+  #[serde(default)]
   pub file_size: i64,
   pub file_unique_id: String,
   pub height: i64,
+  #[serde(default)]
   pub mime_type: String,
+  #[serde(default)]
   pub thumb: Option<Thumb>,
   pub width: i64,
 }
@@ -155,9 +431,12 @@ impl fmt::Display for Video {
 pub struct Audio {
   pub duration: i64,
   pub file_id: String,
+  #[serde(default)]
   pub file_name: String,
+  #[serde(default)]
   pub file_size: i64,
   pub file_unique_id: String,
+  #[serde(default)]
   pub mime_type: String,
 }
 
@@ -167,6 +446,24 @@ impl fmt::Display for Audio {
   }
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Document {
+  pub file_id: String,
+  #[serde(default)]
+  pub file_name: String,
+  #[serde(default)]
+  pub file_size: i64,
+  pub file_unique_id: String,
+  #[serde(default)]
+  pub mime_type: String,
+}
+
+impl fmt::Display for Document {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Document {} {}", self.file_id, self.file_name)
+  }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct SendMessageResponseInner {
   pub message_id: i64,
@@ -178,6 +475,8 @@ pub struct SendMessageResponseInner {
   pub video: Option<Video>,
   #[serde(default)]
   pub audio: Option<Audio>,
+  #[serde(default)]
+  pub document: Option<Document>,
 }
 
 impl fmt::Display for SendMessageResponseInner {
@@ -186,6 +485,7 @@ impl fmt::Display for SendMessageResponseInner {
       SendMessageResponseInner {text: Some(text), ..} => text.clone(),
       SendMessageResponseInner {video: Some(video), ..} => video.to_string(),
       SendMessageResponseInner {audio: Some(audio), ..} => audio.to_string(),
+      SendMessageResponseInner {document: Some(document), ..} => document.to_string(),
       _ => "not implemented".to_string(),
     };
     write!(f, "Sent message {} to {}: {}", self.message_id, self.chat.username, content)
@@ -200,6 +500,8 @@ pub struct SendMessageResponse {
   #[serde(default)]
   pub description: String,
   #[serde(default)]
+  pub parameters: Option<ResponseParameters>,
+  #[serde(default)]
   pub result: Option<SendMessageResponseInner>,
 }
 // Error exapmple:
@@ -211,6 +513,13 @@ impl SendMessageResponse {
   }
 }
 
+impl ApiResponse for SendMessageResponse {
+  fn error(&self) -> Option<TelegramError> {
+    (!self.ok).then(|| TelegramError::new(
+      self.error_code, &self.description, self.parameters.as_ref()))
+  }
+}
+
 impl fmt::Display for SendMessageResponse {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     if self.is_ok() {
@@ -1,68 +1,273 @@
 use tokio::sync::RwLock;
-use anyhow::{Result};
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
 // use itertools::Itertools;
 use lru::LruCache;
+use crate::storage::Storage;
+use crate::file_cache::{FileCache, MediaKey, RequestKey, CachedFile};
+use crate::utils;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub enum Mode {
   Video,
   Audio,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub enum Quality {
   Low,
   High,
   Awful,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// What to do when every format is bigger than upload limit
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Oversize {
+  Reject,
+  /// Re-encode smallest usable format with bitrate fitting the limit
+  Reencode,
+  /// Download anyway and send in several parts
+  Split,
+}
+
+/// Codec audio is converted to in audio mode
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum AudioFormat {
+  /// Send audio stream as downloaded
+  Original,
+  Mp3,
+  /// AAC in mp4 container
+  M4a,
+  Opus,
+  Flac,
+}
+
+impl AudioFormat {
+  pub fn parse(s: &str) -> Option<AudioFormat> {
+    match &*s.to_lowercase() {
+      "original" => Some(AudioFormat::Original),
+      "mp3" => Some(AudioFormat::Mp3),
+      "m4a" | "aac" => Some(AudioFormat::M4a),
+      "opus" => Some(AudioFormat::Opus),
+      "flac" => Some(AudioFormat::Flac),
+      _ => None,
+    }
+  }
+}
+
+/// Parse `[[hh:]mm:]ss[.ms]` into milliseconds
+pub fn parse_time(s: &str) -> Result<i64> {
+  let parts : Vec<_> = s.split(':').collect();
+  if parts.len() > 3 {
+    return Err(anyhow!("Bad time {}, expected [[hh:]mm:]ss[.ms]", s));
+  }
+  let (whole, seconds) = parts.split_at(parts.len() - 1);
+  let seconds = seconds[0].parse::<f64>()
+    .ok()
+    .filter(|x| x.is_finite() && *x >= 0.0 && (whole.is_empty() || *x < 60.0))
+    .ok_or(anyhow!("Bad seconds in {}", s))?;
+  let mut total = 0;
+  for (i, part) in whole.iter().enumerate() {
+    let value = part.parse::<u32>()
+      .ok()
+      // minutes are limited only when hours are given
+      .filter(|x| i == 0 || *x < 60)
+      .ok_or(anyhow!("Bad time {}, expected [[hh:]mm:]ss[.ms]", s))?;
+    total = total * 60 + value as i64;
+  }
+  Ok(total * 60_000 + (seconds * 1000.0).round() as i64)
+}
+
+/// Format milliseconds like 1:02:03.5
+pub fn format_time(ms: i64) -> String {
+  let (hours, minutes, seconds) = (ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60);
+  let mut res = if hours > 0 {
+    format!("{}:{:02}:{:02}", hours, minutes, seconds)
+  } else {
+    format!("{}:{:02}", minutes, seconds)
+  };
+  if ms % 1000 != 0 {
+    res = format!("{}.{}", res, format!("{:03}", ms % 1000).trim_end_matches('0'));
+  }
+  res
+}
+
+/// How to cut video
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum CutMode {
+  /// Copy streams, video starts at nearest keyframe
+  Fast,
+  /// Re-encode short clips whole
+  Accurate,
+}
+
+/// Part of media to keep, in milliseconds, open ends are start and end of media
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct CutInterval {
-  pub start: i64,
-  pub end: i64,  
+  pub start: Option<i64>,
+  pub end: Option<i64>,
 }
 
 impl CutInterval {
-  pub fn parse(s: &str, e: &str) -> Result<Self> {
-    let start = s.parse::<i64>()?;
-    let end = e.parse::<i64>()?;
-    Ok(CutInterval {start, end})
+  /// Parse range like `1:00-2:30`, `1:00-` or `-0:30`
+  pub fn parse(s: &str) -> Result<Self> {
+    let (start, end) = s.split_once('-')
+      .ok_or(anyhow!("Bad interval {}, expected start-end", s))?;
+    let start = Some(start).filter(|x| !x.is_empty()).map(parse_time).transpose()?;
+    let end = Some(end).filter(|x| !x.is_empty()).map(parse_time).transpose()?;
+    match (start, end) {
+      (None, None) => Err(anyhow!("Interval {} is empty", s)),
+      (Some(start), Some(end)) if start >= end =>
+        Err(anyhow!("Interval {} ends before it starts", s)),
+      _ => Ok(CutInterval {start, end}),
+    }
+  }
+
+  /// Start from time in `t` query parameter: `90`, `1m30s`, `1h2m3s`.
+  /// Only on sites where `t` is a timestamp, elsewhere it may be anything.
+  pub fn from_url(url: &url::Url) -> Option<Self> {
+    let host = url.host_str()?;
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    let timestamped = ["youtube.com", "youtu.be", "twitch.tv"].iter()
+      .any(|x| host == *x || host.ends_with(&format!(".{}", x)));
+    if !timestamped {
+      return None;
+    }
+    let (_, t) = url.query_pairs().find(|(k, _)| k == "t")?;
+    let mut start = 0;
+    let mut number = String::new();
+    for c in t.chars() {
+      let unit = match c {
+        '0'..='9' => {
+          number.push(c);
+          continue;
+        },
+        'h' => 3600,
+        'm' => 60,
+        's' => 1,
+        _ => return None,
+      };
+      start += number.parse::<i64>().ok()? * unit;
+      number.clear();
+    }
+    if !number.is_empty() {
+      start += number.parse::<i64>().ok()?;
+    }
+    (start > 0).then_some(CutInterval {start: Some(start * 1000), end: None})
+  }
+
+  /// Length in seconds, [duration] of whole media is needed for open end
+  pub fn duration(&self, duration: Option<f64>) -> Option<f64> {
+    let start = self.start.unwrap_or(0) as f64 / 1000.0;
+    let end = match self.end {
+      Some(end) => end as f64 / 1000.0,
+      None => duration?,
+    };
+    Some(end - start)
   }
 }
 
 impl std::fmt::Display for CutInterval {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "{}-{}", self.start, self.end)
+    write!(f, "{}-{}",
+           self.start.map(format_time).unwrap_or_default(),
+           self.end.map(format_time).unwrap_or_default())
   }
 }
 
+/// Segments to keep, sent as separate files or joined into one
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct Cut {
+  pub segments: Vec<CutInterval>,
+  pub join: bool,
+}
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl Cut {
+  /// Parse command arguments: ranges and optional "join".
+  /// Two plain timestamps are one range for compatibility: `10 20` is `10-20`.
+  pub fn parse(args: &[&str]) -> Result<Self> {
+    let join = args.last() == Some(&"join");
+    let args = if join { &args[..args.len() - 1] } else { args };
+    let segments = match args {
+      [] => return Err(anyhow!("Specify intervals like 1:00-2:30")),
+      [start, end] if !start.contains('-') && !end.contains('-') =>
+        vec![CutInterval::parse(&format!("{}-{}", start, end))?],
+      args => args.iter()
+        .map(|x| CutInterval::parse(x))
+        .collect::<Result<Vec<_>>>()?,
+    };
+    Ok(Cut {segments, join})
+  }
+
+  /// Check that every segment is within media of [duration] seconds
+  pub fn validate(&self, duration: Option<f64>) -> Result<()> {
+    let duration = match duration {
+      Some(duration) => (duration * 1000.0) as i64,
+      None => return Ok(()),
+    };
+    for segment in &self.segments {
+      let past_end = segment.start.is_some_and(|x| x >= duration)
+        // durations are rounded, allow a second more
+        || segment.end.is_some_and(|x| x > duration + 1000);
+      if past_end {
+        return Err(anyhow!("Cut {} is past the end of video, it is only {} long",
+                           segment, format_time(duration)));
+      }
+    }
+    Ok(())
+  }
+
+  /// Total length in seconds, [duration] of whole media is needed for open ends
+  pub fn duration(&self, duration: Option<f64>) -> Option<f64> {
+    self.segments.iter().map(|x| x.duration(duration)).sum()
+  }
+}
+
+impl std::fmt::Display for Cut {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let segments : Vec<_> = self.segments.iter().map(|x| x.to_string()).collect();
+    write!(f, "{}{}", segments.join(" "), if self.join { " joined" } else { "" })
+  }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(default)]
 pub struct UserConfig {
   pub mode: Mode,
   pub aquality: Quality,
   pub vquality: Quality,
   pub vcodec_exclude: Vec<String>,
-  pub cut_interval: Option<CutInterval>,
+  /// One-time, reset after download
+  pub cut: Option<Cut>,
+  pub cut_mode: CutMode,
+  pub oversize: Oversize,
+  /// Send media as document, without inline player
+  pub as_file: bool,
+  pub audio_format: AudioFormat,
+  /// Bitrate of converted audio, kbps
+  pub abitrate: u32,
 }
 
 impl std::fmt::Display for UserConfig {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     let UserConfig {mode, aquality, vquality, vcodec_exclude,
-                    cut_interval} = (*self).clone();
+                    cut, cut_mode, oversize, as_file,
+                    audio_format, abitrate} = (*self).clone();
     let vcodecs = vcodec_exclude.join(",");
-    let cut_interval = match cut_interval {
+    let cut = match cut {
       None => String::new(),
       Some(i) => format!("{}", i)
     };
-    write!(f, "download mode: {:?}\naudio quality: {:?}\nvideo quality: {:?}\nvideo codecs excluded: {}\ncut interval: {}\n",
-           mode, aquality, vquality, vcodecs, cut_interval)
+    write!(f, "download mode: {:?}\naudio quality: {:?}\nvideo quality: {:?}\nvideo codecs excluded: {}\ncut: {}\ncut mode: {:?}\ntoo big files: {:?}\nsend as file: {}\naudio format: {:?} {}kbps\n",
+           mode, aquality, vquality, vcodecs, cut, cut_mode, oversize, as_file,
+           audio_format, abitrate)
   }
 }
 
 impl UserConfig {
   pub fn new() -> UserConfig {
-    UserConfig {mode: Mode::Video, aquality: Quality::Low, vquality: Quality::Low, vcodec_exclude: vec![], cut_interval: None}
+    UserConfig {mode: Mode::Video, aquality: Quality::Low, vquality: Quality::Low, vcodec_exclude: vec![], cut: None, cut_mode: CutMode::Fast, oversize: Oversize::Reencode, as_file: false, audio_format: AudioFormat::Original, abitrate: 192}
   }
 }
 
@@ -72,28 +277,46 @@ impl Default for UserConfig {
   }
 }
 
+/// Keyboard message waiting for user choice
+#[derive(Debug, Clone)]
+pub enum Pending {
+  /// Format picker of URL
+  Pick(url::Url),
+  /// Playlist download confirmation: title and entries
+  Playlist(String, Vec<url::Url>),
+}
+
 pub struct State {
-  pub configs: RwLock<LruCache<i64, UserConfig>>,
+  pub configs: RwLock<Box<dyn Storage>>,
+  /// Keyboard messages waiting for choice, by (chat_id, message_id)
+  pub pending: RwLock<LruCache<(i64, i64), Pending>>,
+  /// file_ids of delivered media
+  pub files: RwLock<FileCache>,
+  /// Held while settings are written
+  configs_saving: tokio::sync::Mutex<()>,
+  /// Held while file cache is written
+  files_saving: tokio::sync::Mutex<()>,
 }
 
 impl State {
-  pub fn new() -> State {
-    let configs = RwLock::new(LruCache::new(std::num::NonZeroUsize::new(100).unwrap()));
-    State {configs}
+  pub fn with_storage(storage: Box<dyn Storage>, files: FileCache) -> State {
+    let pending = RwLock::new(LruCache::new(std::num::NonZeroUsize::new(100).unwrap()));
+    State {configs: RwLock::new(storage), pending, files: RwLock::new(files),
+           configs_saving: tokio::sync::Mutex::new(()),
+           files_saving: tokio::sync::Mutex::new(())}
   }
 
   pub async fn get_userconfig(self: &State, chat_id: i64) -> UserConfig {
     let configs = self.configs.read().await;
-    let val = configs.peek(&chat_id)
-      .map(|x| (*x).clone())
-      .unwrap_or_default();
-
-    val
+    configs.get(chat_id)
+      .unwrap_or_default()
   }
 
   pub async fn set_userconfig(self: &State, chat_id: i64, val: UserConfig) -> UserConfig {
     let mut config = self.configs.write().await;
     config.put(chat_id, val.clone());
+    drop(config);
+    self.save_configs().await;
     val
   }
 
@@ -107,11 +330,12 @@ impl State {
   where F: FnOnce(UserConfig) -> UserConfig
   {
     let mut config = self.configs.write().await;
-    let val = config.peek(&chat_id)
-      .map(|x| (*x).clone())
+    let val = config.get(chat_id)
       .unwrap_or_default();
     let val = f(val);
     config.put(chat_id, val.clone());
+    drop(config);
+    self.save_configs().await;
     val
   }
 
@@ -134,10 +358,154 @@ impl State {
                            |val| UserConfig {vcodec_exclude, .. val}).await
   }
 
-  pub async fn set_cut_inteval(self: &State, chat_id: i64, cut_interval: Option<CutInterval>) -> UserConfig {
+  pub async fn set_cut(self: &State, chat_id: i64, cut: Option<Cut>) -> UserConfig {
+    self.update_userconfig(chat_id,
+                           |val| UserConfig {cut, .. val}).await
+  }
+
+  pub async fn set_oversize(self: &State, chat_id: i64, oversize: Oversize) -> UserConfig {
+    self.update_userconfig(chat_id,
+                           |val| UserConfig {oversize, .. val}).await
+  }
+
+  pub async fn set_cut_mode(self: &State, chat_id: i64, cut_mode: CutMode) -> UserConfig {
     self.update_userconfig(chat_id,
-                           |val| UserConfig {cut_interval, .. val}).await
+                           |val| UserConfig {cut_mode, .. val}).await
+  }
+
+  pub async fn set_as_file(self: &State, chat_id: i64, as_file: bool) -> UserConfig {
+    self.update_userconfig(chat_id,
+                           |val| UserConfig {as_file, .. val}).await
+  }
+
+  /// Bitrate is kept when not given
+  pub async fn set_audio_format(self: &State, chat_id: i64, audio_format: AudioFormat, abitrate: Option<u32>) -> UserConfig {
+    self.update_userconfig(chat_id,
+                           |val| UserConfig {audio_format, abitrate: abitrate.unwrap_or(val.abitrate), .. val}).await
+  }
+
+  /// Remember what keyboard message [message_id] was sent for
+  pub async fn add_pending(self: &State, chat_id: i64, message_id: i64, pending: Pending) {
+    self.pending.write().await.put((chat_id, message_id), pending);
+  }
+
+  /// Forget and return what keyboard message was sent for
+  pub async fn take_pending(self: &State, chat_id: i64, message_id: i64) -> Option<Pending> {
+    self.pending.write().await.pop(&(chat_id, message_id))
   }
 
+  pub async fn cached_request(self: &State, request: &RequestKey) -> Option<(MediaKey, Vec<CachedFile>)> {
+    self.files.write().await.get_by_request(request)
+  }
+
+  pub async fn cached_media(self: &State, key: &MediaKey) -> Option<Vec<CachedFile>> {
+    self.files.write().await.get(key)
+  }
 
+  /// Remember request resolved to [key] and, if known, its delivered [files]
+  pub async fn cache_files(self: &State, request: RequestKey, key: MediaKey, files: Option<Vec<CachedFile>>) {
+    self.files.write().await.put(request, key, files);
+    self.save_files().await;
+  }
+
+  pub async fn forget_media(self: &State, key: &MediaKey) {
+    self.files.write().await.remove(key);
+    self.save_files().await;
+  }
+
+  /// Write settings without holding their lock, [configs_saving] keeps writes in order
+  async fn save_configs(self: &State) {
+    let _saving = self.configs_saving.lock().await;
+    let snapshot = self.configs.read().await.snapshot();
+    if let Err(e) = write_snapshot(snapshot).await {
+      log::error!("Could not save settings: {}", e);
+    }
+  }
+
+  /// Write file cache without holding its lock, [files_saving] keeps writes in order
+  async fn save_files(self: &State) {
+    let _saving = self.files_saving.lock().await;
+    let snapshot = self.files.read().await.snapshot();
+    if let Err(e) = write_snapshot(snapshot).await {
+      log::error!("Could not save file cache: {}", e);
+    }
+  }
+}
+
+/// Write (path, data) off the async runtime
+async fn write_snapshot(snapshot: Result<Option<(String, Vec<u8>)>>) -> Result<()> {
+  match snapshot? {
+    Some((path, data)) => tokio::task::spawn_blocking(
+      move || utils::write_atomic(&path, &data)).await?,
+    None => Ok(()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn interval(start: Option<i64>, end: Option<i64>) -> CutInterval {
+    CutInterval {start, end}
+  }
+
+  #[test]
+  fn parse_time_bounds() {
+    assert_eq!(parse_time("90").unwrap(), 90_000);
+    assert_eq!(parse_time("1:30").unwrap(), 90_000);
+    assert_eq!(parse_time("1:02:03.5").unwrap(), 3_723_500);
+    // minutes are limited only when hours are given
+    assert_eq!(parse_time("90:00").unwrap(), 5_400_000);
+    assert!(parse_time("1:60").is_err());
+    assert!(parse_time("1:60:00").is_err());
+    assert!(parse_time("-1").is_err());
+    assert!(parse_time("1:2:3:4").is_err());
+  }
+
+  #[test]
+  fn format_time_roundtrip() {
+    assert_eq!(format_time(90_000), "1:30");
+    assert_eq!(format_time(3_723_500), "1:02:03.5");
+    assert_eq!(parse_time(&format_time(3_723_500)).unwrap(), 3_723_500);
+  }
+
+  #[test]
+  fn interval_open_ends() {
+    assert_eq!(CutInterval::parse("1:00-2:30").unwrap(), interval(Some(60_000), Some(150_000)));
+    assert_eq!(CutInterval::parse("1:00-").unwrap(), interval(Some(60_000), None));
+    assert_eq!(CutInterval::parse("-0:30").unwrap(), interval(None, Some(30_000)));
+    assert!(CutInterval::parse("-").is_err());
+    assert!(CutInterval::parse("2:00-1:00").is_err());
+  }
+
+  #[test]
+  fn interval_from_url() {
+    let from_url = |x: &str| CutInterval::from_url(&url::Url::parse(x).unwrap());
+    assert_eq!(from_url("https://www.youtube.com/watch?v=x&t=90"), Some(interval(Some(90_000), None)));
+    assert_eq!(from_url("https://youtu.be/x?t=1m30s"), Some(interval(Some(90_000), None)));
+    assert_eq!(from_url("https://youtu.be/x?t=1h2m3s"), Some(interval(Some(3_723_000), None)));
+    assert_eq!(from_url("https://youtu.be/x?t=abc"), None);
+    assert_eq!(from_url("https://example.com/x?t=90"), None);
+  }
+
+  #[test]
+  fn cut_parse() {
+    let cut = Cut::parse(&["10", "20"]).unwrap();
+    assert_eq!(cut, Cut {segments: vec![interval(Some(10_000), Some(20_000))], join: false});
+    let cut = Cut::parse(&["0:10-0:20", "1:00-", "join"]).unwrap();
+    assert_eq!(cut, Cut {segments: vec![interval(Some(10_000), Some(20_000)), interval(Some(60_000), None)],
+                         join: true});
+    assert!(Cut::parse(&[]).is_err());
+    assert!(Cut::parse(&["join"]).is_err());
+  }
+
+  #[test]
+  fn cut_validate() {
+    let cut = Cut::parse(&["1:00-2:01"]).unwrap();
+    assert!(cut.validate(None).is_ok());
+    // durations are rounded, a second past the end is allowed
+    assert!(cut.validate(Some(120.0)).is_ok());
+    assert!(cut.validate(Some(119.0)).is_err());
+    assert!(Cut::parse(&["1:00-"]).unwrap().validate(Some(30.0)).is_err());
+  }
 }
@@ -1,5 +1,7 @@
 use std::fs;
-use anyhow::{Result, anyhow};
+use std::process::{Output, Stdio};
+use anyhow::{Result, anyhow, Context};
+use tokio::process::{Command, Child};
 
 /// Find fileswith names starting with [name]
 pub fn find_files_pat(dir: &String, name: &String) -> Result<Vec<String>> {
@@ -33,3 +35,103 @@ pub fn find_file_pat(dir: &String, name: &String) -> Result<String> {
     Ok(files[0].clone())
   }
 }
+
+/// Format byte count like 12.3MiB
+pub fn human_size(bytes: f64) -> String {
+  let units = ["B", "KiB", "MiB", "GiB"];
+  let mut size = bytes;
+  let mut unit = 0;
+  while size >= 1024.0 && unit < units.len() - 1 {
+    size /= 1024.0;
+    unit += 1;
+  }
+  format!("{:.1}{}", size, units[unit])
+}
+
+/// Write file via temporary file and rename, so readers never see partial content
+pub fn write_atomic(path: &String, data: &[u8]) -> Result<()> {
+  let tmp = format!("{}.tmp", path);
+  fs::write(&tmp, data)?;
+  fs::rename(&tmp, path)?;
+  Ok(())
+}
+
+/// Read versioned json file passing it through [migrate] first, None if file does not exist
+pub fn load_json<T: serde::de::DeserializeOwned>(
+  path: &String, migrate: fn(u64, serde_json::Value) -> Result<serde_json::Value>,
+) -> Result<Option<T>> {
+  let data = match fs::read(path) {
+    Ok(data) => data,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e.into()),
+  };
+  let data = serde_json::from_slice::<serde_json::Value>(&data)
+    .context(format!("Could not parse {}", path))?;
+  let version = data.get("version")
+    .and_then(|x| x.as_u64())
+    .ok_or(anyhow!("No schema version in {}", path))?;
+  let data = migrate(version, data)?;
+  let res = serde_json::from_value(data)
+    .context(format!("Could not parse {}", path))?;
+  Ok(Some(res))
+}
+
+/// Remove files with names starting with [name], ignoring errors
+pub fn remove_files_pat(dir: &String, name: &String) {
+  match find_files_pat(dir, name) {
+    Ok(files) => for file in files {
+      if let Err(e) = fs::remove_file(&file) {
+        log::error!("Could not remove {}: {}", file, e);
+      }
+    },
+    Err(e) => log::error!("Could not list {}: {}", dir, e),
+  }
+}
+
+/// Kills process group of running child when dropped
+pub struct ProcessGroup {
+  pgid: Option<i32>,
+}
+
+impl ProcessGroup {
+  /// Process exited normally, nothing to kill
+  pub fn disarm(&mut self) {
+    self.pgid = None;
+  }
+}
+
+impl Drop for ProcessGroup {
+  fn drop(&mut self) {
+    if let Some(pgid) = self.pgid {
+      log::info!("Killing process group {}", pgid);
+      unsafe { libc::killpg(pgid, libc::SIGKILL); }
+    }
+  }
+}
+
+/// Spawn command in its own process group with piped stdout/stderr.
+/// Dropping returned guard (e.g. cancelled job) kills process with all its children.
+pub fn spawn(cmd: &mut Command) -> Result<(Child, ProcessGroup)> {
+  unsafe {
+    cmd.pre_exec(|| {
+      if libc::setpgid(0, 0) != 0 {
+        return Err(std::io::Error::last_os_error());
+      }
+      Ok(())
+    });
+  }
+  cmd.stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .kill_on_drop(true);
+  let child = cmd.spawn()?;
+  let group = ProcessGroup {pgid: child.id().map(|x| x as i32)};
+  Ok((child, group))
+}
+
+/// Run command (see [spawn]) and collect output.
+pub async fn output(cmd: &mut Command) -> Result<Output> {
+  let (child, mut group) = spawn(cmd)?;
+  let output = child.wait_with_output().await?;
+  group.disarm();
+  Ok(output)
+}
@@ -0,0 +1,75 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use anyhow::Result;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use tokio::sync::mpsc::UnboundedSender;
+use crate::config::WebhookConfig;
+use crate::telegram::{self, IncomeMessage};
+use crate::telegram_messages as messages;
+
+const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+
+fn response(status: StatusCode) -> Response<Body> {
+  let mut res = Response::new(Body::empty());
+  *res.status_mut() = status;
+  res
+}
+
+/// Check secret header, parse update and forward its messages to dispatcher
+async fn handle(
+  secret_token: Arc<String>, tx: UnboundedSender<IncomeMessage>,
+  req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  if req.method() != Method::POST {
+    return Ok(response(StatusCode::METHOD_NOT_ALLOWED));
+  }
+  let authorized = req.headers().get(SECRET_HEADER)
+    .is_some_and(|x| x.as_bytes() == secret_token.as_bytes());
+  if !authorized {
+    log::warn!("webhook: request with wrong secret token");
+    return Ok(response(StatusCode::UNAUTHORIZED));
+  }
+  let data = match hyper::body::to_bytes(req.into_body()).await {
+    Ok(data) => data,
+    Err(e) => {
+      log::error!("webhook: could not read body: {}", e);
+      return Ok(response(StatusCode::BAD_REQUEST));
+    }
+  };
+  let update = match serde_json::from_slice::<messages::UpdateMessage>(&data) {
+    Ok(update) => update,
+    Err(e) => {
+      // answer 200 anyway, otherwise telegram will redeliver it forever
+      log::error!("webhook: could not parse update: {} {:?}",
+                  e, std::str::from_utf8(&data));
+      return Ok(response(StatusCode::OK));
+    }
+  };
+  for msg in telegram::update_to_messages(update) {
+    if tx.send(msg).is_err() {
+      log::error!("webhook: dispatcher is gone");
+      return Ok(response(StatusCode::SERVICE_UNAVAILABLE));
+    }
+  }
+
+  Ok(response(StatusCode::OK))
+}
+
+/// Run http server receiving telegram updates, send them to [tx].
+pub async fn serve(conf: &WebhookConfig, tx: UnboundedSender<IncomeMessage>) -> Result<()> {
+  let secret_token = Arc::new(conf.secret_token.clone());
+  let make_svc = make_service_fn(move |_conn| {
+    let secret_token = secret_token.clone();
+    let tx = tx.clone();
+    async move {
+      Ok::<_, Infallible>(service_fn(move |req| {
+        handle(secret_token.clone(), tx.clone(), req)
+      }))
+    }
+  });
+  log::info!("webhook: listening on {}", conf.listen);
+  Server::try_bind(&conf.listen)?.serve(make_svc).await?;
+
+  Ok(())
+}
@@ -2,6 +2,10 @@ use std::fmt;
 use serde::{Deserialize, Serialize};
 use anyhow::{Result, Error, Context};
 use tokio::process::Command;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use crate::utils;
+use crate::progress::{Progress, ProgressSender};
+use crate::user_state::CutInterval;
 
 
 
@@ -43,6 +47,32 @@ impl Format {
     (video, audio)
   }
 
+  /// Short human readable description, like "1080p avc1 30fps 45.2MiB"
+  pub fn label(&self) -> String {
+    let (video, audio) = self.get_video_audio();
+    // codec names look like "avc1.640028", keep family only
+    let short = |codec: &str| codec.split('.').next().unwrap_or_default().to_string();
+    let mut parts = vec![];
+    if video != "none" {
+      parts.push(self.height.map_or("?p".to_string(), |h| format!("{}p", h)));
+      parts.push(short(&video));
+      if let Some(fps) = self.fps {
+        parts.push(format!("{}fps", fps.round()));
+      }
+    }
+    if audio != "none" {
+      parts.push(short(&audio));
+      if video == "none" {
+        if let Some(abr) = self.abr.or(self.tbr) {
+          parts.push(format!("{}k", abr.round()));
+        }
+      }
+    }
+    parts.push(self.get_filesize()
+               .map_or("?".to_string(), |x| utils::human_size(x as f64)));
+    parts.join(" ")
+  }
+
   pub fn add_audio(&self, audio: &Format) -> Self {
     let filesize_approx =
       self.filesize_approx
@@ -86,6 +116,8 @@ impl fmt::Display for FormatVec {
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Video {
   pub id: String,
+  #[serde(default)]
+  pub extractor_key: String,
   pub title: String,
   pub filename: String,
   pub ext: String,
@@ -115,12 +147,43 @@ pub struct Video {
   pub asr: Option<f64>,
   pub vbr: Option<f64>,
   pub fps: Option<f64>,
+  #[serde(default)]
+  pub thumbnail: Option<String>,
+  #[serde(default)]
+  pub uploader: Option<String>,
+  /// Set by music extractors
+  #[serde(default)]
+  pub artist: Option<String>,
+  #[serde(default)]
+  pub track: Option<String>,
+  #[serde(default)]
+  pub album: Option<String>,
+  /// Set when video is described as playlist entry
+  #[serde(default)]
+  pub playlist_title: Option<String>,
+  /// YYYYMMDD
+  #[serde(default)]
+  pub upload_date: Option<String>,
+  /// YYYYMMDD, set by music extractors
+  #[serde(default)]
+  pub release_date: Option<String>,
+  #[serde(default)]
+  pub webpage_url: Option<String>,
 }
 
 impl Video {
   pub fn get_filesize(&self) -> Option<i64> {
     self.filesize.or(self.filesize_approx)
   }
+
+  /// Release or upload date like 2023-10-05
+  pub fn date(&self) -> Option<String> {
+    let date = self.release_date.as_ref().or(self.upload_date.as_ref())?;
+    match (date.get(0..4), date.get(4..6), date.get(6..8)) {
+      (Some(y), Some(m), Some(d)) => Some(format!("{}-{}-{}", y, m, d)),
+      _ => Some(date.clone()),
+    }
+  }
 }
 
 impl std::fmt::Display for Video {
@@ -131,47 +194,191 @@ impl std::fmt::Display for Video {
 }
 
 
-pub async fn describe(url: url::Url) -> Result<Video> {
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PlaylistEntry {
+  #[serde(default)]
+  pub id: Option<String>,
+  #[serde(default)]
+  pub url: Option<String>,
+  #[serde(default)]
+  pub webpage_url: Option<String>,
+  #[serde(default)]
+  pub title: Option<String>,
+}
+
+impl PlaylistEntry {
+  pub fn get_url(&self) -> Option<url::Url> {
+    self.webpage_url.as_ref().or(self.url.as_ref())
+      .and_then(|x| url::Url::parse(x).ok())
+  }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Playlist {
+  pub id: String,
+  #[serde(default)]
+  pub title: String,
+  /// Total number of entries, may be more than listed
+  #[serde(default)]
+  pub playlist_count: Option<u64>,
+  #[serde(default)]
+  pub entries: Vec<PlaylistEntry>,
+}
+
+impl std::fmt::Display for Playlist {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Playlist: {} {} {} entries", self.id, self.title, self.entries.len())
+  }
+}
+
+pub enum Description {
+  Video(Box<Video>),
+  Playlist(Playlist),
+}
+
+/// Describe URL which may be a video or a playlist (entries are not resolved).
+/// [playlist_items] is yt-dlp items spec like "1-10,15".
+pub async fn describe_any(url: url::Url, playlist_items: Option<String>) -> Result<Description> {
   let mut cmd = Command::new("yt-dlp");
-  cmd.arg("-j").arg(url.to_string());
+  cmd.arg("-J").arg("--flat-playlist");
+  if let Some(items) = playlist_items {
+    cmd.arg("--playlist-items").arg(items);
+  }
+  cmd.arg(url.to_string());
   log::info!("ytdlp::describe {:?}", &cmd);
-  let output = cmd.output();
-  let output = output.await?;
+  let output = utils::output(&mut cmd).await?;
 
   if !output.status.success() {
     // Err(output.stderr.to_string())
     log::error!("stdout: {:?}\nstderr: {:?}",
-                std::str::from_utf8(&output.stdout).unwrap(),
-                std::str::from_utf8(&output.stderr).unwrap());
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr));
     Err(Error::msg("Command describe failed"))
   } else { Ok(()) }?;
 
-  // let _res_raw : serde_json::Value = serde_json::from_slice(&output.stdout)?;
-  let result = serde_json::from_slice::<Video>(&output.stdout);
+  let res_raw : serde_json::Value = serde_json::from_slice(&output.stdout)?;
+  let is_playlist = res_raw.get("_type")
+    .and_then(|x| x.as_str())
+    .is_some_and(|x| x == "playlist");
+  let result =
+    if is_playlist {
+      serde_json::from_value::<Playlist>(res_raw).map(Description::Playlist)
+    } else {
+      serde_json::from_value::<Video>(res_raw).map(|x| Description::Video(Box::new(x)))
+    };
     // .context("Could not parse ytdlp::describe response");
   if result.is_err() {
-    log::error!("stdout: {:?}", std::str::from_utf8(&output.stdout).unwrap());
+    log::error!("stdout: {:?}", String::from_utf8_lossy(&output.stdout));
   }
   let result = result?;
   
   Ok(result)
 }
 
-pub async fn download(url: url::Url, filename: String, format_id: Option<String>) -> Result<()> {
+pub async fn describe(url: url::Url) -> Result<Video> {
+  // playlist is refused anyway, do not list all of it
+  match describe_any(url, Some("1".to_string())).await? {
+    Description::Video(video) => Ok(*video),
+    Description::Playlist(_) => Err(Error::msg("This is a playlist, send it without command")),
+  }
+}
+
+/// Marker of progress lines in yt-dlp stdout
+const PROGRESS_PREFIX: &str = "[ytdlpbot-progress]";
+const PROGRESS_TEMPLATE: &str = "%(progress.downloaded_bytes)s %(progress.total_bytes)s %(progress.total_bytes_estimate)s %(progress.speed)s %(progress.eta)s";
+
+/// Parse line printed with PROGRESS_TEMPLATE, fields may be "NA"
+fn parse_progress(line: &str) -> Option<Progress> {
+  let line = line.strip_prefix(PROGRESS_PREFIX)?;
+  let fields : Vec<Option<f64>> = line.split_whitespace()
+    .map(|x| x.parse::<f64>().ok())
+    .collect();
+  match fields[..] {
+    [done, total, total_estimate, speed, eta] => Some(Progress {
+      done: done.unwrap_or(0.0) as u64,
+      total: total.or(total_estimate).map(|x| x as u64),
+      speed,
+      eta: eta.map(|x| x as u64),
+    }),
+    _ => None,
+  }
+}
+
+/// yt-dlp errors telling that media can not be downloaded partially
+const SECTIONS_UNSUPPORTED: [&str; 2] = ["cannot be partially downloaded", "download-sections"];
+
+/// Requested section can not be downloaded alone, whole media has to be
+#[derive(Debug)]
+pub struct SectionsUnsupported;
+
+impl fmt::Display for SectionsUnsupported {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Sections can not be downloaded for this site")
+  }
+}
+
+impl std::error::Error for SectionsUnsupported {}
+
+/// yt-dlp section like `*1.500-inf`, times in seconds
+fn section_arg(section: &CutInterval) -> String {
+  let seconds = |ms: i64| format!("{}.{:03}", ms / 1000, ms % 1000);
+  format!("*{}-{}",
+          seconds(section.start.unwrap_or(0)),
+          section.end.map(seconds).unwrap_or_else(|| "inf".to_string()))
+}
+
+/// Download [section] only when given, [accurate] puts keyframes at its bounds
+pub async fn download(url: url::Url, filename: String, format_id: Option<String>,
+                      section: Option<&CutInterval>, accurate: bool,
+                      progress: ProgressSender) -> Result<()> {
   let mut cmd = Command::new("yt-dlp");
   cmd.arg("-o").arg(filename);
   if let Some(format_id) = format_id {
     cmd.arg("-f").arg(format_id);
   }
+  if let Some(section) = section {
+    cmd.arg("--download-sections").arg(section_arg(section));
+    if accurate {
+      cmd.arg("--force-keyframes-at-cuts");
+    }
+  }
+  cmd.arg("--no-playlist").arg("--newline")
+    .arg("--progress-template")
+    .arg(format!("download:{}{}", PROGRESS_PREFIX, PROGRESS_TEMPLATE));
   cmd.arg(url.to_string());
   log::info!("ytdlp::download {:?}", &cmd);
-  let output = cmd.output().await?;
+  let (mut child, mut group) = utils::spawn(&mut cmd)?;
+  let stdout = child.stdout.take().ok_or(Error::msg("no stdout"))?;
+  let mut stderr = child.stderr.take().ok_or(Error::msg("no stderr"))?;
+  let read_stdout = async {
+    let mut lines = BufReader::new(stdout).lines();
+    let mut output = vec![];
+    while let Some(line) = lines.next_line().await? {
+      match parse_progress(&line) {
+        Some(p) => { let _ = progress.send(Some(p)); },
+        None => output.push(line),
+      }
+    }
+    Ok::<_, Error>(output)
+  };
+  let read_stderr = async {
+    let mut output = vec![];
+    stderr.read_to_end(&mut output).await?;
+    Ok::<_, Error>(output)
+  };
+  let (stdout, stderr) = tokio::try_join!(read_stdout, read_stderr)?;
+  let status = child.wait().await?;
+  group.disarm();
 
-  if !output.status.success() {
+  if !status.success() {
     // Err(output.stderr.to_string())
+    let stderr = String::from_utf8_lossy(&stderr);
     log::error!("stdout: {:?}\nstderr: {:?}",
-                std::str::from_utf8(&output.stdout).unwrap(),
-                std::str::from_utf8(&output.stderr).unwrap());
+                stdout.join("\n"),
+                stderr);
+    if section.is_some() && SECTIONS_UNSUPPORTED.iter().any(|x| stderr.contains(x)) {
+      return Err(SectionsUnsupported.into());
+    }
     Err(Error::msg("Command download failed"))
   } else { Ok(()) }?;
 